@@ -1,10 +1,14 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use libp2p::core::{Multiaddr, PeerId};
 use libp2p::multiaddr::Protocol;
+use tokio::sync::broadcast::error::RecvError;
 
+// The `peer` module provides the API for Zinnia, this demo exercises only a part of it.
 pub mod peer;
-use peer::PeerNode;
+use peer::{NetworkEvent, PeerNode, PeerNodeConfig, PingMonitorConfig, PingStats};
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
@@ -25,8 +29,23 @@ async fn main() {
     // DEMO USAGE OF THE `peer` MODULE
 
     // 1. Setup the peer and spawn the network task for it to run in the background.
-    let mut peer =
-        PeerNode::spawn(Default::default()).expect("should be able to create a new peer");
+    let peer =
+        PeerNode::spawn(PeerNodeConfig::default()).expect("should be able to create a new peer");
+
+    // Report inbound requests we cannot handle, the remote peer may be asking us for
+    // a protocol we don't support.
+    let mut events = peer.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(NetworkEvent::InboundFailure { peer_id, error, .. }) => {
+                    println!("Error: Cannot handle inbound request from peer {peer_id}: {error}");
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
 
     // 2. Dial a remote peer using a peer_id & remote_addr
     // Zinnia will not register with DHT in the initial version.
@@ -37,52 +56,31 @@ async fn main() {
         .expect("Dial should succeed");
     println!("Connected in {}ms", started.elapsed().as_millis());
 
-    let request = ping::new_request_payload();
-
-    // 3. Send a request to the given peer
-    let started = Instant::now();
-    let response = peer
-        .request_protocol(
-            peer_id,
-            remote_addr.clone(),
-            ping::PROTOCOL_NAME,
-            request.clone(),
+    // 3. Monitor the round-trip time to the remote peer
+    let mut events = peer.subscribe();
+    let monitor = peer
+        .monitor_pings(
+            vec![(peer_id, remote_addr)],
+            PingMonitorConfig {
+                interval: PING_INTERVAL,
+                ..Default::default()
+            },
         )
-        .await
-        .expect("request ping protocol should succeed");
-    let duration = started.elapsed();
-
-    // 4. Process the response and report results
-    if response != request {
-        println!(
-            "Ping {} payload mismatch. Sent {:?}, received {:?}",
-            peer_id, request, response,
-        );
-    } else {
-        println!("Round-trip time: {}ms", duration.as_millis(),)
-    }
-
-    // TRY AGAIN
-
-    let request = ping::new_request_payload();
-
-    // Send a request to the given peer
-    let started = Instant::now();
-    let response = peer
-        .request_protocol(peer_id, remote_addr, ping::PROTOCOL_NAME, request.clone())
-        .await
-        .expect("request ping protocol should succeed");
-    let duration = started.elapsed();
-
-    // Process the response and report results
-    if response != request {
-        println!(
-            "Ping {} payload mismatch. Sent {:?}, received {:?}",
-            peer_id, request, response,
-        );
-    } else {
-        println!("Round-trip time: {}ms", duration.as_millis(),)
+        .expect("The ping monitor config should be valid");
+
+    // 4. Report the stats after each ping
+    let mut rounds = 0;
+    while rounds < 3 {
+        match events.recv().await {
+            Ok(NetworkEvent::PingStats(stats)) => {
+                rounds += 1;
+                print_ping_stats(&stats);
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
     }
+    drop(monitor);
 
     // SHUTDOWN
     peer.shutdown()
@@ -90,12 +88,35 @@ async fn main() {
         .expect("should be able to cleanly stop the peer")
 }
 
+fn print_ping_stats(stats: &PingStats) {
+    let last_rtt = match stats.last_rtt {
+        Some(rtt) => format!("{}ms", rtt.as_millis()),
+        None => "lost".into(),
+    };
+    match &stats.rtt {
+        Some(rtt) => println!(
+            "Round-trip time: {} (min {}ms, avg {}ms, max {}ms, jitter {}ms, lost {}/{})",
+            last_rtt,
+            rtt.min.as_millis(),
+            rtt.avg.as_millis(),
+            rtt.max.as_millis(),
+            rtt.jitter.as_millis(),
+            stats.lost,
+            stats.sent,
+        ),
+        None => println!(
+            "Round-trip time: {} (lost {}/{})",
+            last_rtt, stats.lost, stats.sent
+        ),
+    }
+}
+
+#[cfg(test)]
 mod ping {
     use rand::{distributions, thread_rng, Rng};
 
     use crate::peer::RequestPayload;
 
-    pub const PROTOCOL_NAME: &[u8] = b"/ipfs/ping/1.0.0";
     pub const PING_SIZE: usize = 32;
     pub type PingRequestPayload = [u8; PING_SIZE];
 
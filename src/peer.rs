@@ -21,36 +21,133 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use std::collections::{hash_map, HashMap};
+use std::collections::{hash_map, HashMap, HashSet};
 use std::error::Error;
-use tokio::sync::{mpsc, oneshot};
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, OwnedMutexGuard};
 use tokio::task::JoinHandle;
 
 use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::ListenerId;
+use libp2p::core::transport::{OptionalTransport, OrTransport};
 use libp2p::core::{transport, upgrade, Multiaddr, PeerId};
-use libp2p::futures::StreamExt;
+use libp2p::futures::future::{try_join_all, BoxFuture};
+use libp2p::futures::io::{ReadHalf, WriteHalf};
+use libp2p::futures::stream::FuturesUnordered;
+use libp2p::futures::{AsyncReadExt, AsyncWriteExt, FutureExt, StreamExt};
+use libp2p::identify;
 use libp2p::identity;
+use libp2p::kad::record::Key;
+use libp2p::kad::{AddProviderError, GetProvidersOk, KademliaEvent, QueryId, QueryResult};
 use libp2p::multiaddr::Protocol;
 use libp2p::noise;
-use libp2p::swarm::{ConnectionHandlerUpgrErr, NetworkBehaviour, Swarm, SwarmEvent};
+use libp2p::relay::v2::client as relay_client;
+use libp2p::relay::v2::relay as relay_server;
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::{AddressScore, NegotiatedSubstream, NetworkBehaviour, Swarm, SwarmEvent};
 use libp2p::yamux;
-use libp2p::Transport;
+use libp2p::{Transport, TransportError};
 
 mod behaviour;
+mod dht;
+mod error;
 mod handler;
+mod keys;
+mod monitor;
+mod negotiation;
+mod ping;
+mod relay;
+mod retry;
 
+pub use behaviour::{
+    CloseDelimited, Codec, FixedLength, InboundFailure, LengthPrefixed, RequestOptions,
+    RequestPayload, RequestResponseConfig, ResponseMetadata, ResponsePayload,
+};
 use behaviour::{
-    ProtocolInfo, RequestId, RequestResponse, RequestResponseEvent, RequestResponseMessage,
+    OutboundFailure, ProtocolInfo, RequestId, RequestResponse, RequestResponseEvent,
+    RequestResponseMessage, ResponseChannel,
+};
+use dht::Dht;
+pub use dht::{DhtConfig, Provider};
+pub use error::{
+    DialFailure, PeerNodeError, RelayHop, RelayedDialFailure, StreamFailure, TransportFailure,
 };
-pub use behaviour::{RequestPayload, ResponsePayload};
+pub use keys::NodeIdentity;
+pub use monitor::{PingMonitor, PingMonitorConfig, PingStats, RttStats};
+use negotiation::RecordingMuxer;
+pub use ping::PingConfig;
+use ping::PingId;
+pub use relay::{ActiveCircuit, RelayServerConfig, RelayStats};
+pub use retry::RetryPolicy;
+
+/// The configuration of a [`PeerNode`].
+#[derive(Debug, Clone)]
+pub struct PeerNodeConfig {
+    /// The keypair identifying the node, a new one is generated on every start by default.
+    pub identity: NodeIdentity,
+    /// The addresses to listen on for incoming connections, e.g. `/ip4/0.0.0.0/tcp/0`.
+    ///
+    /// Use [`PeerNode::listen_addrs`] to find out the addresses actually bound.
+    pub listen_addrs: Vec<Multiaddr>,
+    pub request_response: RequestResponseConfig,
+    /// How long [`PeerNode::shutdown`] waits for requests in flight to finish before failing
+    /// them with [`PeerNodeError::ShuttingDown`].
+    pub shutdown_timeout: Duration,
+    /// The configuration of the ping protocol, see [`PeerNode::ping`].
+    pub ping: PingConfig,
+    /// How long [`PeerNode::ping`] waits for the result of its ping, including dialing
+    /// the peer. Should exceed [`PingConfig::timeout`].
+    pub ping_timeout: Duration,
+    /// The agent version advertised to remote peers via the identify protocol,
+    /// e.g. `zinnia/0.1.0`. See [`PeerInfo::agent_version`].
+    pub agent_version: String,
+    /// The Kademlia DHT used to find the addresses of peers, see [`PeerNode::dial_peer`].
+    /// Disabled by default.
+    pub dht: Option<DhtConfig>,
+    /// The relays to keep reservations on, making the node reachable through them.
+    ///
+    /// The circuit addresses of the relays are reported as our listen addresses once the
+    /// reservations are made, see [`NetworkEvent::ReservationLost`] for failures.
+    pub relays: Vec<(PeerId, Multiaddr)>,
+    /// Relay connections for other peers, making them reachable through us.
+    /// Disabled by default, see [`PeerNode::relay_stats`].
+    pub relay_server: Option<RelayServerConfig>,
+}
 
-pub type PeerNodeConfig = behaviour::RequestResponseConfig;
+impl Default for PeerNodeConfig {
+    fn default() -> Self {
+        Self {
+            identity: Default::default(),
+            listen_addrs: Default::default(),
+            request_response: Default::default(),
+            shutdown_timeout: Duration::from_secs(5),
+            ping: Default::default(),
+            ping_timeout: Duration::from_secs(30),
+            agent_version: format!("zinnia/{}", env!("CARGO_PKG_VERSION")),
+            dht: None,
+            relays: Vec::new(),
+            relay_server: None,
+        }
+    }
+}
 
 /// A Zinnia peer node wrapping rust-libp2p and providing higher-level APIs
 /// for consumption by Deno ops.
+///
+/// `PeerNode` is a cheap handle to the network event loop, clone it to use the node from
+/// multiple tasks. The event loop shuts down when [`PeerNode::shutdown`] is called or when
+/// the last handle is dropped.
+#[derive(Clone)]
 pub struct PeerNode {
+    peer_id: PeerId,
     command_sender: mpsc::Sender<Command>,
-    event_loop_task: Option<JoinHandle<()>>,
+    event_sender: broadcast::Sender<NetworkEvent>,
+    event_loop_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    ping_timeout: Duration,
 }
 
 impl PeerNode {
@@ -59,168 +156,937 @@ impl PeerNode {
     /// This will create the underlying network client and spawn a tokio task handling
     /// networking event loop. The returned [`PeerNode`] can be used to control the task.
     pub fn spawn(config: PeerNodeConfig) -> Result<PeerNode, Box<dyn Error>> {
-        // Create a new random public/private key pair
-        // Zinnia will always generate a new key pair on (re)start
-        let id_keys = identity::Keypair::generate_ed25519();
+        // Unless configured otherwise, Zinnia generates a new key pair on (re)start
+        let id_keys = config.identity.into_keypair()?;
         let peer_id = id_keys.public().to_peer_id();
 
-        let tcp_transport = create_transport(&id_keys)?;
+        let zinnia = RequestResponse::new(config.request_response);
 
-        // In the initial version, Zinnia nodes ARE NOT dialable.
-        // Each module must connect to a remote server (dial the orchestrator)
-        //
-        // let tcp_listen_addr: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse()?;
-        // tcp_transport.listen_on(tcp_listen_addr.clone())?;
+        // Record the protocols remote peers ask for when we don't support them.
+        let rejected_protocols = zinnia.rejected_protocols();
+        // Dial and listen on `/p2p-circuit` addresses through relays.
+        let (relay_transport, relay_client) =
+            relay_client::Client::new_transport_and_behaviour(peer_id);
+        let transport = create_transport_with_relay(&id_keys, Some(relay_transport))?;
+        let tcp_transport = Transport::map(transport, move |(remote_peer_id, muxer), endpoint| {
+            let muxer = RecordingMuxer::new(muxer, remote_peer_id, endpoint, &rejected_protocols);
+            (remote_peer_id, StreamMuxerBox::new(muxer))
+        })
+        .boxed();
+
+        let external_addrs = config
+            .relay_server
+            .as_ref()
+            .map(|relay_server| relay_server.external_addrs.clone())
+            .unwrap_or_default();
+        let relay_stats = config.relay_server.as_ref().map(|_| RelayStats::default());
 
         // Build the Swarm, connecting the lower layer transport logic with the
         // higher layer network behaviour logic.
-        let swarm = Swarm::with_tokio_executor(
-            tcp_transport,
-            ComposedBehaviour {
-                zinnia: RequestResponse::new(config),
-            },
-            peer_id,
-        );
+        let behaviour = ComposedBehaviour {
+            zinnia,
+            ping: ping::Behaviour::new(config.ping),
+            identify: identify::Behaviour::new(
+                identify::Config::new(IDENTIFY_PROTOCOL_VERSION.into(), id_keys.public())
+                    .with_agent_version(config.agent_version),
+            ),
+            kademlia: config.dht.map(|dht| dht.into_behaviour(peer_id)).into(),
+            relay_client,
+            relay_server: config
+                .relay_server
+                .map(|relay_server| relay_server.into_behaviour(peer_id))
+                .into(),
+        };
+        let mut swarm = Swarm::with_tokio_executor(tcp_transport, behaviour, peer_id);
+        for addr in external_addrs {
+            swarm.add_external_address(addr, AddressScore::Infinite);
+        }
+
+        // By default, Zinnia nodes ARE NOT dialable.
+        // Each module must connect to a remote server (dial the orchestrator)
+        for addr in config.listen_addrs {
+            swarm.listen_on(addr)?;
+        }
+        // Unless we are reachable through relays.
+        let mut reservations = HashMap::new();
+        for (relay_peer_id, relay_addr) in config.relays {
+            let listener_id =
+                swarm.listen_on(relay::circuit_addr(relay_peer_id, relay_addr.clone()))?;
+            reservations.insert(listener_id, (relay_peer_id, relay_addr));
+        }
 
         let (command_sender, command_receiver) = mpsc::channel::<Command>(1);
+        let (event_sender, _) = broadcast::channel(NETWORK_EVENTS_BUFFER_SIZE);
 
-        let event_loop = EventLoop::new(swarm, command_receiver);
+        let event_loop = EventLoop::new(
+            swarm,
+            command_receiver,
+            event_sender.clone(),
+            config.shutdown_timeout,
+            reservations,
+            relay_stats,
+        );
         let event_loop_task = tokio::spawn(event_loop.run());
 
         Ok(Self {
+            peer_id,
             command_sender,
-            event_loop_task: event_loop_task.into(),
+            event_sender,
+            event_loop_task: Arc::new(Mutex::new(Some(event_loop_task))),
+            ping_timeout: config.ping_timeout,
         })
     }
 
-    pub async fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(handle) = self.event_loop_task.take() {
-            self.command_sender.send(Command::Shutdown).await?;
-            handle.await?
+    /// The ID of the local peer, derived from the configured [`NodeIdentity`].
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Subscribe to the events observed by the node, see [`NetworkEvent`].
+    ///
+    /// Only the events that happen after subscribing are delivered. A subscriber that is
+    /// not keeping up misses the oldest events and receives [`broadcast::error::RecvError::Lagged`].
+    pub fn subscribe(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.event_sender.subscribe()
+    }
+
+    /// Start listening for incoming connections on the given address.
+    ///
+    /// Resolves once the listener is bound, the returned [`Listener`] carries the actual
+    /// address, e.g. with the port number assigned by the OS for `/tcp/0`. Further changes
+    /// are reported via [`Listener::next_event`].
+    pub async fn listen_on(&self, addr: Multiaddr) -> Result<Listener, PeerNodeError> {
+        self.call(|sender| Command::Listen { addr, sender }).await?
+    }
+
+    /// The addresses we are listening on.
+    pub async fn listen_addrs(&self) -> Result<Vec<Multiaddr>, PeerNodeError> {
+        self.call(|sender| Command::ListenAddrs { sender }).await
+    }
+
+    /// Shut down the node, all handles sharing the node become unusable.
+    ///
+    /// New commands are rejected right away, requests in flight are given
+    /// [`PeerNodeConfig::shutdown_timeout`] to finish. Afterwards, all outstanding work fails
+    /// with [`PeerNodeError::ShuttingDown`] and all connections are closed.
+    pub async fn shutdown(&self) -> Result<(), PeerNodeError> {
+        let task = self.event_loop_task.lock().await.take();
+        if let Some(handle) = task {
+            // The event loop may have stopped already, e.g. after a panic.
+            let _ = self.command_sender.send(Command::Shutdown).await;
+            handle.await.map_err(|err| PeerNodeError::EventLoopFailed {
+                message: err.to_string(),
+            })?
         }
         Ok(())
     }
 
     /// Dial the given peer at the given address.
-    pub async fn dial(
-        &mut self,
+    ///
+    /// Use a `/p2p-circuit` address to reach the peer through a relay, e.g.
+    /// `/ip4/1.2.3.4/tcp/4001/p2p/RELAY/p2p-circuit`. The peer must hold a reservation
+    /// on the relay.
+    pub async fn dial(&self, peer_id: PeerId, peer_addr: Multiaddr) -> Result<(), PeerNodeError> {
+        self.call(|sender| Command::Dial {
+            peer_id,
+            peer_addr,
+            sender,
+        })
+        .await?
+    }
+
+    /// Dial the given peer at any of its known addresses.
+    ///
+    /// The addresses of peers dialed before are remembered. Otherwise, the addresses are
+    /// looked up in the DHT when [`PeerNodeConfig::dht`] is enabled.
+    pub async fn dial_peer(&self, peer_id: PeerId) -> Result<(), PeerNodeError> {
+        self.call(|sender| Command::DialPeer { peer_id, sender })
+            .await?
+    }
+
+    /// Advertise in the DHT that we provide the given key, e.g. the multihash of a CID.
+    ///
+    /// Resolves once the provider record was published to the peers closest to the key.
+    /// The record is published again every [`DhtConfig::provider_publication_interval`]
+    /// until [`PeerNode::stop_providing`] is called, also when publishing it failed.
+    pub async fn start_providing(&self, key: &[u8]) -> Result<(), PeerNodeError> {
+        self.call(|sender| Command::StartProviding {
+            key: key.into(),
+            sender,
+        })
+        .await?
+    }
+
+    /// Stop advertising the given key in the DHT.
+    ///
+    /// The peers we published the provider record to keep it until it expires,
+    /// see [`DhtConfig::provider_record_ttl`].
+    pub async fn stop_providing(&self, key: &[u8]) -> Result<(), PeerNodeError> {
+        self.call(|sender| Command::StopProviding {
+            key: key.into(),
+            sender,
+        })
+        .await?
+    }
+
+    /// Find up to `limit` peers providing the given key in the DHT.
+    ///
+    /// The providers are delivered via the returned receiver as they are found, the receiver
+    /// is closed once `limit` providers were found or the lookup finished. We are never
+    /// reported as a provider of our own keys.
+    pub async fn find_providers(
+        &self,
+        key: &[u8],
+        limit: usize,
+    ) -> Result<mpsc::UnboundedReceiver<Provider>, PeerNodeError> {
+        self.call(|sender| Command::FindProviders {
+            key: key.into(),
+            limit,
+            sender,
+        })
+        .await?
+    }
+
+    // NEW API FOR ZINNIA
+
+    pub async fn request_protocol(
+        &self,
         peer_id: PeerId,
         peer_addr: Multiaddr,
-    ) -> Result<(), Box<dyn Error + Send>> {
-        let (sender, receiver) = oneshot::channel();
-        self.command_sender
-            .send(Command::Dial {
+        protocol: &[u8],
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>, PeerNodeError> {
+        self.request_protocol_with_options(
+            peer_id,
+            peer_addr,
+            protocol,
+            payload,
+            Default::default(),
+        )
+        .await
+        .map(|response| response.payload)
+    }
+
+    /// Like [`PeerNode::request_protocol`], overriding the configuration with the given options.
+    pub async fn request_protocol_with_options(
+        &self,
+        peer_id: PeerId,
+        peer_addr: Multiaddr,
+        protocol: &[u8],
+        payload: Vec<u8>,
+        options: RequestOptions,
+    ) -> Result<Response, PeerNodeError> {
+        self.request_protocols(peer_id, peer_addr, &[protocol], payload, options)
+            .await
+    }
+
+    /// Send the request using the first of the given protocols supported by the remote peer.
+    ///
+    /// List the protocols in the order of preference, e.g. `/foo/2.0.0` before `/foo/1.0.0`,
+    /// the protocol picked by the remote peer is returned in [`Response::protocol`].
+    ///
+    /// The [`RequestOptions::deadline`] applies to dialing the peer too. With a
+    /// [`RequestOptions::retry`] policy, failing to dial the peer is retried like any
+    /// other transient failure.
+    pub async fn request_protocols(
+        &self,
+        peer_id: PeerId,
+        peer_addr: Multiaddr,
+        protocols: &[&[u8]],
+        payload: Vec<u8>,
+        options: RequestOptions,
+    ) -> Result<Response, PeerNodeError> {
+        let protocols: Vec<ProtocolInfo> = protocols.iter().map(|&p| p.into()).collect();
+        let deadline = options.deadline;
+        let request = async {
+            if options.retry.is_none() {
+                self.dial(peer_id, peer_addr.clone())
+                    .await
+                    .map_err(|err| err.for_protocols(&protocols))?;
+            }
+            self.call(|sender| Command::Request {
                 peer_id,
                 peer_addr,
+                protocols: protocols.clone(),
+                payload,
+                options,
+                sender,
+            })
+            .await?
+        };
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), request)
+                .await
+                .unwrap_or_else(|_| Err(PeerNodeError::timeout(peer_id, &protocols))),
+            None => request.await,
+        }
+    }
+
+    /// Find out which of the given protocols the remote peer supports, without sending
+    /// any request.
+    ///
+    /// Each protocol is negotiated on its own substream, the substream is reset right
+    /// after the negotiation without sending anything. Returns the supported protocols in
+    /// the given order.
+    ///
+    /// The remote peer hands the substream of an accepted protocol over to its handler,
+    /// multistream-select has no way to stop short of that. Muxers report the reset as the
+    /// end of the substream, the handler fails to read a request framed by the codec of the
+    /// protocol. A handler reading requests until the end of the substream would take the
+    /// probe for an empty request though, so probing fails with
+    /// [`PeerNodeError::UnprobeableProtocol`] before sending anything when any of the
+    /// protocols is [`CloseDelimited`], see [`PeerNode::set_codec`].
+    pub async fn supports(
+        &self,
+        peer_id: PeerId,
+        peer_addr: Multiaddr,
+        protocols: &[&[u8]],
+    ) -> Result<Vec<Vec<u8>>, PeerNodeError> {
+        let codecs = self
+            .call(|sender| Command::Codecs {
+                protocols: protocols.iter().map(|&protocol| protocol.into()).collect(),
                 sender,
             })
+            .await?;
+        if let Some((protocol, _)) = protocols
+            .iter()
+            .zip(codecs)
+            .find(|(_, codec)| codec.closes_writer())
+        {
+            return Err(PeerNodeError::unprobeable_protocol(protocol));
+        }
+
+        let probed: Vec<ProtocolInfo> = protocols.iter().map(|&p| p.into()).collect();
+        self.dial(peer_id, peer_addr)
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(|err| err.for_protocols(&probed))?;
+        let probes = protocols.iter().map(|&protocol| async move {
+            let result = self
+                .call(|sender| Command::OpenStream {
+                    peer_id,
+                    protocol: protocol.into(),
+                    sender,
+                })
+                .await?;
+            match result {
+                Ok(stream) => {
+                    // Dropping the stream resets it, half-closing it would send an empty
+                    // request to the remote peer.
+                    drop(stream);
+                    Ok(true)
+                }
+                Err(PeerNodeError::UnsupportedProtocols { .. }) => Ok(false),
+                Err(err) => Err(err),
+            }
+        });
+        let supported = try_join_all(probes).await?;
+        Ok(protocols
+            .iter()
+            .zip(supported)
+            .filter(|(_, supported)| *supported)
+            .map(|(protocol, _)| protocol.to_vec())
+            .collect())
     }
 
-    // NEW API FOR ZINNIA
+    /// Start accepting inbound requests for the given protocol.
+    ///
+    /// Requests sent by remote peers are delivered via the returned receiver, each of them
+    /// carrying a [`Responder`] for sending back the response. Registering the same protocol
+    /// again replaces the previous receiver. Requests for protocols that were not registered
+    /// are rejected. The receiver is closed once the node was shut down.
+    pub async fn register_protocol(&self, protocol: &[u8]) -> mpsc::Receiver<InboundRequest> {
+        let (sender, receiver) = mpsc::channel(INBOUND_REQUESTS_BUFFER_SIZE);
+        // Dropping the sender along with the command closes the receiver.
+        let _ = self
+            .command_sender
+            .send(Command::RegisterProtocol {
+                protocol: protocol.into(),
+                sender,
+            })
+            .await;
+        receiver
+    }
 
-    pub async fn request_protocol(
-        &mut self,
+    /// Measure the round-trip time to the given peer using the ping protocol.
+    ///
+    /// Dials the peer first if needed, then sends a ping right away over the ping stream of
+    /// a connection to the peer. Each connection keeps a single ping stream, also used for
+    /// the pings sent in the background every [`PingConfig::interval`], so a ping already in
+    /// flight on the stream is awaited first. Fails after [`PeerNodeConfig::ping_timeout`],
+    /// peers known not to support ping fail right away.
+    pub async fn ping(
+        &self,
         peer_id: PeerId,
         peer_addr: Multiaddr,
-        protocol: &[u8],
-        payload: Vec<u8>,
-    ) -> Result<Vec<u8>, Box<dyn Error + Send>> {
-        let (sender, receiver) = oneshot::channel();
-        self.dial(peer_id, peer_addr).await?;
-        self.command_sender
-            .send(Command::Request {
-                peer_id,
+    ) -> Result<Duration, PeerNodeError> {
+        let request = async {
+            self.dial(peer_id, peer_addr)
+                .await
+                .map_err(|err| err.for_protocols(&[ping::PROTOCOL_NAME.into()]))?;
+            self.call(|sender| Command::Ping { peer_id, sender })
+                .await?
+        };
+        tokio::time::timeout(self.ping_timeout, request)
+            .await
+            .unwrap_or_else(|_| Err(PeerNodeError::ping(peer_id, &ping::Failure::Timeout)))
+    }
+
+    /// Ping the given peers every [`PingMonitorConfig::interval`] and keep their stats, see
+    /// [`PeerNode::ping`].
+    ///
+    /// The peers are dialed again whenever they get disconnected, failing to dial a peer
+    /// counts as a lost ping. The monitor stops when dropped, it keeps the node running
+    /// until then, see [`PingMonitor`].
+    ///
+    /// Fails with [`PeerNodeError::InvalidConfig`] when the interval, the timeout or the
+    /// window of the config is zero.
+    pub fn monitor_pings(
+        &self,
+        peers: Vec<(PeerId, Multiaddr)>,
+        config: PingMonitorConfig,
+    ) -> Result<PingMonitor, PeerNodeError> {
+        config.validate()?;
+        Ok(PingMonitor::spawn(self.clone(), peers, config))
+    }
+
+    /// What the given peer told us about itself via the identify protocol.
+    ///
+    /// Peers identify themselves right after connecting, see [`NetworkEvent::PeerIdentified`].
+    /// Returns `None` when the peer is not connected or has not identified itself yet.
+    pub async fn peer_info(&self, peer_id: PeerId) -> Result<Option<PeerInfo>, PeerNodeError> {
+        self.call(|sender| Command::PeerInfo { peer_id, sender })
+            .await
+    }
+
+    /// The activity of the circuit relay server, `None` when [`PeerNodeConfig::relay_server`]
+    /// is not enabled.
+    pub async fn relay_stats(&self) -> Result<Option<RelayStats>, PeerNodeError> {
+        self.call(|sender| Command::RelayStats { sender }).await
+    }
+
+    /// Use the given codec for framing the requests and responses of the given protocol,
+    /// both inbound and outbound. Protocols use [`CloseDelimited`] by default.
+    pub async fn set_codec(&self, protocol: &[u8], codec: impl Codec + 'static) {
+        // The codec doesn't matter anymore once the node was shut down.
+        let _ = self
+            .command_sender
+            .send(Command::SetCodec {
                 protocol: protocol.into(),
-                payload,
+                codec: Arc::new(codec),
+            })
+            .await;
+    }
+
+    /// Dial the given peer at the given address and open a substream for the given protocol.
+    ///
+    /// The returned [`StreamHandle`] can be used with [`PeerNode::write_all`],
+    /// [`PeerNode::close_writer`] and [`PeerNode::read`], reads and writes may run
+    /// concurrently. The stream is closed once the handle is dropped.
+    pub async fn dial_protocol(
+        &self,
+        peer_id: PeerId,
+        peer_addr: Multiaddr,
+        protocol: &[u8],
+    ) -> Result<StreamHandle, PeerNodeError> {
+        self.dial(peer_id, peer_addr)
+            .await
+            .map_err(|err| err.for_protocols(&[protocol.into()]))?;
+        self.call(|sender| Command::OpenStream {
+            peer_id,
+            protocol: protocol.into(),
+            sender,
+        })
+        .await?
+    }
+
+    /// Write the entire buffer to the stream.
+    pub async fn write_all(&self, handle: &StreamHandle, buf: &[u8]) -> Result<(), PeerNodeError> {
+        self.call(|sender| Command::WriteStream {
+            stream_id: handle.id,
+            data: buf.into(),
+            sender,
+        })
+        .await?
+        .map_err(|err| handle.error(err))
+    }
+
+    /// Close the writing side of the stream, signalling the end of the request
+    /// to the remote peer. The stream can still be read from.
+    pub async fn close_writer(&self, handle: &StreamHandle) -> Result<(), PeerNodeError> {
+        self.call(|sender| Command::CloseStreamWriter {
+            stream_id: handle.id,
+            sender,
+        })
+        .await?
+        .map_err(|err| handle.error(err))
+    }
+
+    /// Read up to `buf.len()` bytes from the stream.
+    ///
+    /// Returns the number of bytes read, `0` means the remote peer closed its writer.
+    /// An empty `buf` is rejected with [`io::ErrorKind::InvalidInput`], reading into it
+    /// could not be told apart from the end of the stream.
+    pub async fn read(
+        &self,
+        handle: &StreamHandle,
+        buf: &mut [u8],
+    ) -> Result<usize, PeerNodeError> {
+        if buf.is_empty() {
+            return Err(handle.error(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot read into an empty buffer",
+            )));
+        }
+        let data = self
+            .call(|sender| Command::ReadStream {
+                stream_id: handle.id,
+                max_len: buf.len(),
                 sender,
             })
+            .await?
+            .map_err(|err| handle.error(err))?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Sends a command to the event loop and waits for the reply.
+    async fn call<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> Result<T, PeerNodeError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .send(command(sender))
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not be dropped.")
-    }
-
-    // pub async fn dial_protocol(
-    //     &self,
-    //     peer_id: PeerId,
-    //     peer_addr: Multiaddr,
-    //     proto_name: &[u8],
-    // ) -> Result<StreamHandle, Box<dyn Error + Send>> {
-    //     todo!(
-    //         "TODO: dial {peer_id} at {peer_addr} and start protocol {}",
-    //         String::from_utf8_lossy(proto_name)
-    //     );
-    // }
-
-    // pub async fn write_all(
-    //     &self,
-    //     handle: &StreamHandle,
-    //     buf: &[u8],
-    // ) -> Result<(), Box<dyn Error + Send>> {
-    //     todo!("TODO: write {} bytes to {:?}", buf.len(), handle)
-    // }
-
-    // pub async fn close_writer(
-    //     &self,
-    //     handle: &mut StreamHandle,
-    // ) -> Result<(), Box<dyn Error + Send>> {
-    //     todo!("TODO: close writer {:?}", handle)
-    // }
-
-    // pub async fn read(
-    //     &self,
-    //     handle: &mut StreamHandle,
-    //     buf: &[u8],
-    // ) -> Result<usize, Box<dyn Error + Send>> {
-    //     todo!("TODO: read up to {} bytes from {:?}", buf.len(), handle)
-    // }
+            .map_err(|_| PeerNodeError::ShuttingDown)?;
+        receiver.await.map_err(|_| PeerNodeError::ShuttingDown)
+    }
 }
 
 pub fn create_transport(
     id_keys: &identity::Keypair,
 ) -> Result<transport::Boxed<(PeerId, StreamMuxerBox)>, noise::NoiseError> {
+    create_transport_with_relay(id_keys, None)
+}
+
+/// Like [`create_transport`], handling `/p2p-circuit` addresses by the given relay transport.
+fn create_transport_with_relay(
+    id_keys: &identity::Keypair,
+    relay_transport: Option<relay_client::transport::ClientTransport>,
+) -> Result<transport::Boxed<(PeerId, StreamMuxerBox)>, noise::NoiseError> {
+    let relay_transport = match relay_transport {
+        Some(relay_transport) => OptionalTransport::some(relay_transport),
+        None => OptionalTransport::none(),
+    };
     // Setup the transport + multiplex + auth
     // Zinnia will hard-code this configuration initially.
     // We need to pick reasonable defaults that will allow Zinnia nodes to interoperate with
     // as many other libp2p nodes as possible.
-    let tcp_transport = libp2p::dns::TokioDnsConfig::system(libp2p::tcp::tokio::Transport::new(
-        libp2p::tcp::Config::new(),
-    ))?
+    let tcp_transport = OrTransport::new(
+        relay_transport,
+        libp2p::dns::TokioDnsConfig::system(libp2p::tcp::tokio::Transport::new(
+            libp2p::tcp::Config::new(),
+        ))?,
+    )
     .upgrade(upgrade::Version::V1)
-    .authenticate(noise::NoiseAuthenticated::xx(&id_keys)?)
+    .authenticate(noise::NoiseAuthenticated::xx(id_keys)?)
     .multiplex(upgrade::SelectUpgrade::new(
         yamux::YamuxConfig::default(),
         libp2p::mplex::MplexConfig::default(),
     ))
     .timeout(std::time::Duration::from_secs(5))
     .boxed();
-    return Ok(tcp_transport);
+    Ok(tcp_transport)
+}
+
+/// The protocol version we advertise via the identify protocol, the one used by IPFS nodes.
+const IDENTIFY_PROTOCOL_VERSION: &str = "ipfs/0.1.0";
+
+/// A listener started by [`PeerNode::listen_on`].
+#[derive(Debug)]
+pub struct Listener {
+    address: Multiaddr,
+    events: mpsc::UnboundedReceiver<ListenerEvent>,
+}
+
+impl Listener {
+    /// The first address the listener was bound to.
+    pub fn address(&self) -> &Multiaddr {
+        &self.address
+    }
+
+    /// Wait for the next change of the listener.
+    ///
+    /// Returns `None` after the listener was closed.
+    pub async fn next_event(&mut self) -> Option<ListenerEvent> {
+        self.events.recv().await
+    }
+}
+
+/// Changes of a [`Listener`].
+#[derive(Debug)]
+pub enum ListenerEvent {
+    /// The listener is listening on a new address, e.g. after a new network interface came up.
+    NewAddress(Multiaddr),
+    /// The listener is no longer listening on the address.
+    ExpiredAddress(Multiaddr),
+    /// The listener encountered a non-fatal error.
+    Error(io::Error),
+    /// The listener was closed, with an error if it failed.
+    Closed(Result<(), io::Error>),
+}
+
+/// How long to wait before requesting a lost reservation on a relay again.
+const RESERVATION_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How many events are buffered for each subscriber of [`PeerNode::subscribe`].
+const NETWORK_EVENTS_BUFFER_SIZE: usize = 64;
+
+/// The events observed by a [`PeerNode`], see [`PeerNode::subscribe`].
+#[derive(Debug, Clone)]
+pub enum NetworkEvent {
+    /// A connection to the peer was established.
+    ConnectionEstablished {
+        peer_id: PeerId,
+        /// The address of the remote peer.
+        address: Multiaddr,
+        /// Whether we have dialed the peer.
+        outbound: bool,
+    },
+    /// A connection to the peer was closed.
+    ConnectionClosed {
+        peer_id: PeerId,
+        /// The address of the remote peer.
+        address: Multiaddr,
+        /// The error that caused the connection to close, `None` when closed gracefully.
+        cause: Option<String>,
+    },
+    /// Dialing a peer failed.
+    DialFailed {
+        /// The peer we have dialed, `None` when dialing an address without a peer ID.
+        peer_id: Option<PeerId>,
+        addresses: Vec<TransportFailure>,
+        message: String,
+    },
+    /// A remote peer connected to us but the connection could not be upgraded,
+    /// e.g. because the handshake failed.
+    IncomingConnectionFailed {
+        /// The address we have accepted the connection on.
+        local_address: Multiaddr,
+        /// The address of the remote peer.
+        remote_address: Multiaddr,
+        message: String,
+    },
+    /// Handling a request received from the remote peer failed.
+    InboundFailure {
+        peer_id: PeerId,
+        /// The protocol negotiated for the request, `None` when the request failed
+        /// before it was received.
+        protocol: Option<String>,
+        error: InboundFailure,
+    },
+    /// We started listening on a new address.
+    NewListenAddr { address: Multiaddr },
+    /// We stopped listening on the address.
+    ExpiredListenAddr { address: Multiaddr },
+    /// The stats of a peer were updated after a ping, see [`PeerNode::monitor_pings`].
+    PingStats(PingStats),
+    /// The reservation on a relay in [`PeerNodeConfig::relays`] was lost or could not be made.
+    ///
+    /// We are not reachable through the relay until the reservation is requested again,
+    /// a few seconds later. Reservations held are renewed before they expire.
+    ReservationLost {
+        relay_peer_id: PeerId,
+        message: String,
+    },
+    /// A connected peer identified itself, see [`PeerNode::peer_info`].
+    PeerIdentified { peer_id: PeerId, info: PeerInfo },
+}
+
+/// What a remote peer told us about itself via the identify protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The name and version of the software run by the peer, e.g. `zinnia/0.1.0`.
+    pub agent_version: String,
+    /// The version of the protocol family the peer speaks, e.g. `ipfs/0.1.0`.
+    pub protocol_version: String,
+    /// The protocols the peer accepts inbound streams for.
+    ///
+    /// rust-libp2p nodes advertise the protocols supported when the node was created,
+    /// protocols registered later are missing.
+    pub protocols: Vec<String>,
+    /// The addresses the peer is listening on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Our address as observed by the peer, e.g. the public address of our NAT.
+    pub observed_addr: Multiaddr,
+}
+
+impl From<identify::Info> for PeerInfo {
+    fn from(info: identify::Info) -> Self {
+        // rust-libp2p peers also list their listen addresses among the external ones once
+        // other peers observed them there.
+        let mut listen_addrs = info.listen_addrs;
+        let mut seen = HashSet::new();
+        listen_addrs.retain(|addr| seen.insert(addr.clone()));
+        Self {
+            agent_version: info.agent_version,
+            protocol_version: info.protocol_version,
+            protocols: info.protocols,
+            listen_addrs,
+            observed_addr: info.observed_addr,
+        }
+    }
+}
+
+/// A response received by [`PeerNode::request_protocol_with_options`].
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub payload: ResponsePayload,
+    /// The protocol negotiated for the request, see [`PeerNode::request_protocols`].
+    pub protocol: Vec<u8>,
+    /// How many times the request was sent, more than once when it was retried,
+    /// see [`RequestOptions::retry`].
+    pub attempts: u32,
+    /// How the response was received, describes the last attempt.
+    pub metadata: ResponseMetadata,
+}
+
+/// How many inbound requests can wait for the consumer of [`PeerNode::register_protocol`].
+/// Requests received while the buffer is full are dropped without a response.
+const INBOUND_REQUESTS_BUFFER_SIZE: usize = 16;
+
+/// A request received from a remote peer, see [`PeerNode::register_protocol`].
+#[derive(Debug)]
+pub struct InboundRequest {
+    pub peer_id: PeerId,
+    pub protocol: Vec<u8>,
+    pub payload: RequestPayload,
+    pub responder: Responder,
+}
+
+/// Sends the response to an [`InboundRequest`] back to the remote peer.
+///
+/// Dropping the responder without calling [`Responder::respond`] closes the substream
+/// without sending any response.
+#[derive(Debug)]
+pub struct Responder {
+    channel: ResponseChannel,
+}
+
+impl Responder {
+    /// Checks whether the remote peer is still waiting for the response, i.e. the request
+    /// has not timed out and the connection is still open.
+    pub fn is_open(&self) -> bool {
+        self.channel.is_open()
+    }
+
+    /// Send the response to the remote peer.
+    ///
+    /// Returns the response back when it cannot be sent anymore, e.g. because the request
+    /// timed out or the connection was closed.
+    pub fn respond(self, response: ResponsePayload) -> Result<(), ResponsePayload> {
+        self.channel.send(response)
+    }
+}
+
+/// A handle representing a substream opened by our network behaviour
+///
+/// Dropping the handle closes the stream.
+#[derive(Debug)]
+pub struct StreamHandle {
+    id: RequestId,
+    peer_id: PeerId,
+    protocol: ProtocolInfo,
+    closer: mpsc::UnboundedSender<RequestId>,
+}
+
+impl StreamHandle {
+    /// The peer on the other end of the stream.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// The protocol negotiated for the stream.
+    pub fn protocol(&self) -> &[u8] {
+        &self.protocol
+    }
+
+    fn error(&self, err: io::Error) -> PeerNodeError {
+        PeerNodeError::stream(self.peer_id, &self.protocol, err)
+    }
 }
 
-// /// A handle representing a substream opened by our network behaviour
-// #[derive(Debug)]
-// pub struct StreamHandle;
+impl Drop for StreamHandle {
+    fn drop(&mut self) {
+        // All streams are closed once the node was shut down.
+        let _ = self.closer.send(self.id);
+    }
+}
 
 pub struct EventLoop {
     swarm: Swarm<ComposedBehaviour>,
     command_receiver: mpsc::Receiver<Command>,
-    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), Box<dyn Error + Send>>>>,
+    /// Callers waiting for the dial in progress, keyed by the peer being dialed.
+    pending_dial: HashMap<PeerId, Vec<oneshot::Sender<Result<(), PeerNodeError>>>>,
+    /// DHT lookups of the addresses of peers in `pending_dial`.
+    pending_lookups: HashMap<QueryId, PeerId>,
+    /// Callers waiting for their provider records to be published.
+    pending_provides: HashMap<QueryId, oneshot::Sender<Result<(), PeerNodeError>>>,
+    provider_searches: HashMap<QueryId, ProviderSearch>,
+    /// Callers waiting for the result of the next ping, keyed by the peer being pinged.
+    pending_pings:
+        HashMap<PeerId, HashMap<PingId, oneshot::Sender<Result<Duration, PeerNodeError>>>>,
+    /// The connected peers not supporting ping, there will be no more pings to them.
+    ping_unsupported: HashSet<PeerId>,
     pending_requests: HashMap<RequestId, PendingRequest>,
+    /// Requests waiting for their backoff to elapse, keyed by the ID of the failed attempt.
+    waiting_retries: HashMap<RequestId, PendingRequest>,
+    /// Fire when the backoff of the request in `waiting_retries` with the given ID elapses.
+    retry_timers: FuturesUnordered<BoxFuture<'static, RequestId>>,
+    /// Fire at the deadlines of the requests, see [`RequestOptions::deadline`].
+    request_deadlines: FuturesUnordered<BoxFuture<'static, ()>>,
+    pending_streams: HashMap<RequestId, PendingStream>,
+    /// What the connected peers told us about themselves, see [`PeerNode::peer_info`].
+    peer_infos: HashMap<PeerId, PeerInfo>,
+    streams: HashMap<RequestId, OpenStream>,
+    /// Receives the IDs of the streams whose [`StreamHandle`] was dropped.
+    closed_streams: mpsc::UnboundedReceiver<RequestId>,
+    stream_closer: mpsc::UnboundedSender<RequestId>,
+    inbound_handlers: HashMap<ProtocolInfo, mpsc::Sender<InboundRequest>>,
+    pending_listeners: HashMap<ListenerId, PendingListener>,
+    listeners: HashMap<ListenerId, mpsc::UnboundedSender<ListenerEvent>>,
+    /// The listeners keeping reservations on relays, see [`PeerNodeConfig::relays`].
+    reservations: HashMap<ListenerId, (PeerId, Multiaddr)>,
+    /// Fire when it's time to request a lost reservation on the given relay again.
+    reservation_timers: FuturesUnordered<BoxFuture<'static, (PeerId, Multiaddr)>>,
+    /// The activity of the relay server, `None` when the relay server is disabled.
+    relay_stats: Option<RelayStats>,
+    event_sender: broadcast::Sender<NetworkEvent>,
+    shutdown_timeout: Duration,
+}
+
+/// How long to wait for the remote peers to acknowledge closing the connections on shutdown.
+const CLOSE_CONNECTIONS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A listener waiting to be bound to its first address.
+struct PendingListener {
+    address: Multiaddr,
+    sender: oneshot::Sender<Result<Listener, PeerNodeError>>,
+    events: mpsc::UnboundedReceiver<ListenerEvent>,
 }
 
 pub struct PendingRequest {
-    sender: oneshot::Sender<Result<ResponsePayload, Box<dyn Error + Send>>>,
+    peer_id: PeerId,
+    protocols: Vec<ProtocolInfo>,
+    /// A copy of the payload to send the request again, kept only when retries are enabled.
+    payload: Option<RequestPayload>,
+    options: RequestOptions,
+    /// How many times the request was sent so far.
+    attempts: u32,
+    /// Why the last dial of the peer failed, [`OutboundFailure::DialFailure`] doesn't tell.
+    dial_error: Option<PeerNodeError>,
+    sender: oneshot::Sender<Result<Response, PeerNodeError>>,
+}
+
+impl PendingRequest {
+    /// How long to wait before sending the request again, `None` when the request
+    /// must fail with the given error.
+    fn retry_backoff(&self, error: &OutboundFailure) -> Option<Duration> {
+        let policy = self.options.retry.as_ref()?;
+        // The caller may have given up on the request already.
+        if self.attempts >= policy.max_attempts
+            || !policy.is_retryable(error)
+            || self.sender.is_closed()
+        {
+            return None;
+        }
+        let backoff = policy.backoff(self.attempts);
+        match self.options.deadline {
+            Some(deadline) if Instant::now() + backoff >= deadline => None,
+            _ => Some(backoff),
+        }
+    }
+
+    fn fail(self, error: OutboundFailure) {
+        let mut error = match (error, self.dial_error) {
+            (OutboundFailure::DialFailure, Some(dial_error)) => {
+                dial_error.for_protocols(&self.protocols)
+            }
+            (error, _) => PeerNodeError::outbound(self.peer_id, &self.protocols, error),
+        };
+        if self.attempts > 1 {
+            error = PeerNodeError::RetryFailed {
+                attempts: self.attempts,
+                last_error: Box::new(error),
+            };
+        }
+        // The caller may have given up on the request already.
+        let _ = self.sender.send(Err(error));
+    }
+}
+
+/// A lookup started by [`Command::FindProviders`].
+struct ProviderSearch {
+    sender: mpsc::UnboundedSender<Provider>,
+    /// The providers reported so far, the lookup may find the same provider more than once.
+    found: HashSet<PeerId>,
+    limit: usize,
+}
+
+/// A stream waiting for the substream negotiation to finish.
+struct PendingStream {
+    protocol: ProtocolInfo,
+    sender: oneshot::Sender<Result<StreamHandle, PeerNodeError>>,
+}
+
+/// A stream opened via [`Command::OpenStream`].
+///
+/// Stream operations run in their own tasks so that they don't block the event loop.
+/// The substream is split so that reads don't wait for writes and vice versa, the mutexes
+/// serialize the operations issued concurrently for the same half.
+struct OpenStream {
+    peer_id: PeerId,
+    reader: Arc<Mutex<ReadHalf<NegotiatedSubstream>>>,
+    writer: Arc<Mutex<WriteHalf<NegotiatedSubstream>>>,
+    _keep_alive: Arc<()>,
 }
 
 impl EventLoop {
-    fn new(swarm: Swarm<ComposedBehaviour>, command_receiver: mpsc::Receiver<Command>) -> Self {
+    fn new(
+        swarm: Swarm<ComposedBehaviour>,
+        command_receiver: mpsc::Receiver<Command>,
+        event_sender: broadcast::Sender<NetworkEvent>,
+        shutdown_timeout: Duration,
+        reservations: HashMap<ListenerId, (PeerId, Multiaddr)>,
+        relay_stats: Option<RelayStats>,
+    ) -> Self {
+        let (stream_closer, closed_streams) = mpsc::unbounded_channel();
         Self {
             swarm,
             command_receiver,
             pending_dial: Default::default(),
+            pending_lookups: Default::default(),
+            pending_provides: Default::default(),
+            provider_searches: Default::default(),
+            pending_pings: Default::default(),
+            ping_unsupported: Default::default(),
             pending_requests: Default::default(),
+            waiting_retries: Default::default(),
+            retry_timers: Default::default(),
+            request_deadlines: Default::default(),
+            pending_streams: Default::default(),
+            peer_infos: Default::default(),
+            streams: Default::default(),
+            closed_streams,
+            stream_closer,
+            inbound_handlers: Default::default(),
+            pending_listeners: Default::default(),
+            listeners: Default::default(),
+            reservations,
+            reservation_timers: Default::default(),
+            relay_stats,
+            event_sender,
+            shutdown_timeout,
         }
     }
 
@@ -230,90 +1096,415 @@ impl EventLoop {
                 event = self.swarm.next() => self.handle_event(event.expect("Swarm stream to be infinite.")).await,
                 command = self.command_receiver.recv() => match command {
                     Some(c) => self.handle_command(c).await,
-                    // Command channel closed, thus shutting down the network event loop.
+                    // Command channel closed, either by `Command::Shutdown` or because
+                    // all handles were dropped, thus shutting down the network event loop.
                     None =>  break,
                 },
+                Some(request_id) = self.retry_timers.next() => self.retry_request(request_id),
+                Some(()) = self.request_deadlines.next() => self.expire_requests(),
+                Some((relay_peer_id, relay_addr)) = self.reservation_timers.next() => {
+                    self.reserve(relay_peer_id, relay_addr)
+                }
+                Some(stream_id) = self.closed_streams.recv() => {
+                    // Operations still in progress keep the substream alive until they finish.
+                    self.streams.remove(&stream_id);
+                }
             }
         }
+        self.shutdown().await;
     }
 
-    async fn handle_event(
-        &mut self,
-        event: SwarmEvent<ComposedEvent, ConnectionHandlerUpgrErr<std::io::Error>>,
-    ) {
-        match event {
-            SwarmEvent::Behaviour(ComposedEvent::Zinnia(result)) => {
-                match result {
-                    RequestResponseEvent::OutboundFailure {
-                        request_id,
-                        error,
-                        peer: _,
-                    } => {
-                        // println!("Cannot request {}: {}", peer, error);
-                        let pending_request = self
-                            .pending_requests
-                            .remove(&request_id)
-                            .expect("Request should be still be pending.");
-                        pending_request
-                            .sender
-                            .send(Err(Box::new(error)))
-                            .expect("Request should have an active sender to receive the result.");
-                    }
+    /// Lets the requests in flight finish, fails the remaining work and closes all connections.
+    async fn shutdown(mut self) {
+        // Dials only lead to new requests, which we are not going to send anymore.
+        for (_, senders) in self.pending_dial.drain() {
+            for sender in senders {
+                let _ = sender.send(Err(PeerNodeError::ShuttingDown));
+            }
+        }
+        self.pending_lookups.clear();
+        for (_, sender) in self.pending_provides.drain() {
+            let _ = sender.send(Err(PeerNodeError::ShuttingDown));
+        }
+        // Closes the receivers of the providers.
+        self.provider_searches.clear();
+        for (_, pending) in self.pending_listeners.drain() {
+            let _ = pending.sender.send(Err(PeerNodeError::ShuttingDown));
+        }
+        // Closing the connections ends the reservations, don't request them again.
+        self.reservations.clear();
+        self.reservation_timers.clear();
+        for (_, senders) in self.pending_pings.drain() {
+            for (_, sender) in senders {
+                let _ = sender.send(Err(PeerNodeError::ShuttingDown));
+            }
+        }
+        for (_, pending) in self.waiting_retries.drain() {
+            let _ = pending.sender.send(Err(PeerNodeError::ShuttingDown));
+        }
+        self.retry_timers.clear();
 
-                    RequestResponseEvent::Message {
-                        peer: _,
+        let drain_deadline = tokio::time::sleep(self.shutdown_timeout);
+        tokio::pin!(drain_deadline);
+        while !self.pending_requests.is_empty() || !self.pending_streams.is_empty() {
+            tokio::select! {
+                event = self.swarm.next() => self.handle_event(event.expect("Swarm stream to be infinite.")).await,
+                // Requests past their deadline don't hold up the shutdown.
+                Some(()) = self.request_deadlines.next() => self.expire_requests(),
+                _ = &mut drain_deadline => break,
+            }
+        }
+
+        for (_, pending) in self.pending_requests.drain() {
+            let _ = pending.sender.send(Err(PeerNodeError::ShuttingDown));
+        }
+        for (_, pending) in self.pending_streams.drain() {
+            let _ = pending.sender.send(Err(PeerNodeError::ShuttingDown));
+        }
+        self.streams.clear();
+        self.inbound_handlers.clear();
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer_id in peers {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+        let close_deadline = tokio::time::sleep(CLOSE_CONNECTIONS_TIMEOUT);
+        tokio::pin!(close_deadline);
+        while self.swarm.connected_peers().next().is_some() {
+            tokio::select! {
+                event = self.swarm.next() => self.handle_event(event.expect("Swarm stream to be infinite.")).await,
+                _ = &mut close_deadline => break,
+            }
+        }
+    }
+
+    async fn handle_event<E: Error>(&mut self, event: SwarmEvent<ComposedEvent, E>) {
+        match event {
+            SwarmEvent::Behaviour(ComposedEvent::Zinnia(result)) => {
+                match result {
+                    RequestResponseEvent::OutboundFailure {
+                        request_id,
+                        error,
+                        peer,
+                    } => {
+                        // println!("Cannot request {}: {}", peer, error);
+                        if let Some(pending_request) = self.pending_requests.remove(&request_id) {
+                            match pending_request.retry_backoff(&error) {
+                                Some(backoff) => {
+                                    self.waiting_retries.insert(request_id, pending_request);
+                                    self.retry_timers.push(
+                                        tokio::time::sleep(backoff)
+                                            .map(move |()| request_id)
+                                            .boxed(),
+                                    );
+                                }
+                                None => pending_request.fail(error),
+                            }
+                        } else if let Some(pending_stream) =
+                            self.pending_streams.remove(&request_id)
+                        {
+                            let error = PeerNodeError::outbound(
+                                peer,
+                                std::slice::from_ref(&pending_stream.protocol),
+                                error,
+                            );
+                            // The caller may have given up on the stream already.
+                            let _ = pending_stream.sender.send(Err(error));
+                        }
+                        // Otherwise the request was failed on shutdown already.
+                    }
+
+                    RequestResponseEvent::Message {
+                        peer: _,
                         message:
                             RequestResponseMessage::Response {
                                 request_id,
+                                protocol,
                                 response,
+                                metadata,
                             },
                     } => {
-                        let pending_request = self
-                            .pending_requests
-                            .remove(&request_id)
-                            .expect("Request should be still be pending.");
-
-                        pending_request
-                            .sender
-                            .send(Ok(response))
-                            .expect("Request should have an active sender to receive the result.");
-                    }
-
-                    // incoming requests - we don't support that!
-                    //
-                    RequestResponseEvent::InboundFailure { peer, error } => {
-                        println!(
-                            "Error: Cannot handle inbound request from peer {}: {}",
-                            peer, error
+                        // The request may have been failed on shutdown already.
+                        if let Some(pending_request) = self.pending_requests.remove(&request_id) {
+                            // The caller may have given up on the request already.
+                            let _ = pending_request.sender.send(Ok(Response {
+                                payload: response,
+                                protocol: protocol.to_vec(),
+                                attempts: pending_request.attempts,
+                                metadata,
+                            }));
+                        }
+                    }
+
+                    RequestResponseEvent::StreamOpened {
+                        peer,
+                        request_id,
+                        stream,
+                        keep_alive,
+                    } => {
+                        // The stream may have been failed on shutdown already, dropping
+                        // the substream closes it.
+                        let pending_stream = match self.pending_streams.remove(&request_id) {
+                            Some(pending_stream) => pending_stream,
+                            None => return,
+                        };
+                        let (reader, writer) = stream.split();
+                        self.streams.insert(
+                            request_id,
+                            OpenStream {
+                                peer_id: peer,
+                                reader: Arc::new(Mutex::new(reader)),
+                                writer: Arc::new(Mutex::new(writer)),
+                                _keep_alive: keep_alive,
+                            },
                         );
+                        let handle = StreamHandle {
+                            id: request_id,
+                            peer_id: peer,
+                            protocol: pending_stream.protocol,
+                            closer: self.stream_closer.clone(),
+                        };
+                        // Nobody may be interested in the stream anymore, dropping the
+                        // handle closes the stream then.
+                        let _ = pending_stream.sender.send(Ok(handle));
+                    }
+
+                    RequestResponseEvent::Message {
+                        peer,
+                        message:
+                            RequestResponseMessage::Request {
+                                protocol,
+                                request,
+                                channel,
+                            },
+                    } => {
+                        if let Some(handler) = self.inbound_handlers.get(&protocol) {
+                            let request = InboundRequest {
+                                peer_id: peer,
+                                protocol: protocol.to_vec(),
+                                payload: request,
+                                responder: Responder { channel },
+                            };
+                            // When the consumer is not keeping up or has gone away, the request
+                            // is dropped and the remote peer receives no response.
+                            let _ = handler.try_send(request);
+                        }
+                    }
+
+                    RequestResponseEvent::InboundFailure {
+                        peer,
+                        protocol,
+                        error,
+                    } => {
+                        self.publish(NetworkEvent::InboundFailure {
+                            peer_id: peer,
+                            protocol: protocol.map(|p| String::from_utf8_lossy(&p).into_owned()),
+                            error,
+                        });
                     }
                 }
             }
 
-            SwarmEvent::NewListenAddr { .. } => {}
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
+                self.publish(NetworkEvent::NewListenAddr {
+                    address: address.clone(),
+                });
+                if let Some(pending) = self.pending_listeners.remove(&listener_id) {
+                    let listener = Listener {
+                        address,
+                        events: pending.events,
+                    };
+                    let _ = pending.sender.send(Ok(listener));
+                } else {
+                    self.notify_listener(listener_id, ListenerEvent::NewAddress(address));
+                }
+            }
+            SwarmEvent::ExpiredListenAddr {
+                listener_id,
+                address,
+            } => {
+                self.publish(NetworkEvent::ExpiredListenAddr {
+                    address: address.clone(),
+                });
+                self.notify_listener(listener_id, ListenerEvent::ExpiredAddress(address));
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                if let Some(pending) = self.pending_listeners.remove(&listener_id) {
+                    let err = reason
+                        .err()
+                        .unwrap_or_else(|| io::Error::other("The listener was closed."));
+                    let _ = pending
+                        .sender
+                        .send(Err(PeerNodeError::listen(pending.address, &err)));
+                } else if let Some((relay_peer_id, relay_addr)) =
+                    self.reservations.remove(&listener_id)
+                {
+                    let message = match reason {
+                        Ok(()) => "The connection to the relay was closed".into(),
+                        Err(err) => err.to_string(),
+                    };
+                    self.reservation_lost(relay_peer_id, relay_addr, message);
+                } else {
+                    self.notify_listener(listener_id, ListenerEvent::Closed(reason));
+                }
+                self.listeners.remove(&listener_id);
+            }
+            SwarmEvent::ListenerError { listener_id, error } => {
+                if let Some(pending) = self.pending_listeners.remove(&listener_id) {
+                    let _ = pending
+                        .sender
+                        .send(Err(PeerNodeError::listen(pending.address, &error)));
+                    self.swarm.remove_listener(listener_id);
+                } else {
+                    self.notify_listener(listener_id, ListenerEvent::Error(error));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Ping(ping::Event {
+                peer,
+                result,
+                requests,
+            })) => {
+                let result = match result {
+                    Ok(ping::Success::Ping { rtt }) => Ok(rtt),
+                    // We answered a ping of the remote peer.
+                    Ok(ping::Success::Pong) => return,
+                    Err(failure) => {
+                        if let ping::Failure::Unsupported = failure {
+                            // The ping handler of the connection stops pinging.
+                            self.ping_unsupported.insert(peer);
+                        }
+                        Err(PeerNodeError::ping(peer, &failure))
+                    }
+                };
+                if let hash_map::Entry::Occupied(mut pending) = self.pending_pings.entry(peer) {
+                    for ping_id in requests {
+                        if let Some(sender) = pending.get_mut().remove(&ping_id) {
+                            let _ = sender.send(result.clone());
+                        }
+                    }
+                    if pending.get().is_empty() {
+                        pending.remove();
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Identify(event)) => match event {
+                identify::Event::Received { peer_id, info } => {
+                    let info = PeerInfo::from(info);
+                    self.add_dht_addresses(peer_id, &info);
+                    self.peer_infos.insert(peer_id, info.clone());
+                    self.publish(NetworkEvent::PeerIdentified { peer_id, info });
+                }
+                // Peers not speaking identify are fine, we just don't learn anything about them.
+                identify::Event::Sent { .. }
+                | identify::Event::Pushed { .. }
+                | identify::Event::Error { .. } => {}
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(event)) => self.handle_dht_event(event),
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient) => {}
+            SwarmEvent::Behaviour(ComposedEvent::RelayServer(event)) => {
+                if let Some(stats) = self.relay_stats.as_mut() {
+                    stats.record(&event);
+                }
+            }
             SwarmEvent::IncomingConnection { .. } => {}
             SwarmEvent::ConnectionEstablished {
                 peer_id, endpoint, ..
             } => {
+                self.publish(NetworkEvent::ConnectionEstablished {
+                    peer_id,
+                    address: endpoint.get_remote_address().clone(),
+                    outbound: endpoint.is_dialer(),
+                });
                 if endpoint.is_dialer() {
-                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
+                    for sender in self.pending_dial.remove(&peer_id).unwrap_or_default() {
                         let _ = sender.send(Ok(()));
                     }
                 }
             }
-            SwarmEvent::ConnectionClosed { .. } => {}
-            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                cause,
+            } => {
+                self.publish(NetworkEvent::ConnectionClosed {
+                    peer_id,
+                    address: endpoint.get_remote_address().clone(),
+                    cause: cause.map(|err| err.to_string()),
+                });
+                if num_established == 0 {
+                    // Any further operation on streams to this peer fails with "stream closed".
+                    self.streams.retain(|_, stream| stream.peer_id != peer_id);
+                    self.peer_infos.remove(&peer_id);
+                    self.ping_unsupported.remove(&peer_id);
+                    if let Some(stats) = self.relay_stats.as_mut() {
+                        stats.peer_disconnected(&peer_id);
+                    }
+                    // The pings in flight on the closed connections are lost.
+                    for (_, sender) in self.pending_pings.remove(&peer_id).unwrap_or_default() {
+                        let _ = sender.send(Err(PeerNodeError::ConnectionClosed {
+                            peer_id,
+                            protocol: String::from_utf8_lossy(ping::PROTOCOL_NAME).into_owned(),
+                        }));
+                    }
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                self.publish(NetworkEvent::DialFailed {
+                    peer_id,
+                    addresses: TransportFailure::from_dial_error(&error),
+                    message: error.to_string(),
+                });
                 if let Some(peer_id) = peer_id {
-                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
-                        let _ = sender.send(Err(Box::new(error)));
+                    let error = match relay::relay_of_failed_dial(&error) {
+                        Some(relay_peer_id) => {
+                            // A relayed connection starts with connecting to the relay.
+                            let hop = if self.swarm.is_connected(&relay_peer_id) {
+                                RelayHop::Destination
+                            } else {
+                                RelayHop::Relay
+                            };
+                            PeerNodeError::relayed_dial(peer_id, relay_peer_id, hop, &error)
+                        }
+                        None => PeerNodeError::dial(peer_id, &error),
+                    };
+                    for sender in self.pending_dial.remove(&peer_id).unwrap_or_default() {
+                        let _ = sender.send(Err(error.clone()));
+                    }
+                    // The requests waiting for the connection fail right after, without
+                    // telling why.
+                    for pending_request in self
+                        .pending_requests
+                        .values_mut()
+                        .filter(|pending_request| pending_request.peer_id == peer_id)
+                    {
+                        pending_request.dial_error = Some(error.clone());
                     }
                 }
             }
-            SwarmEvent::IncomingConnectionError { .. } => {}
+            SwarmEvent::IncomingConnectionError {
+                local_addr,
+                send_back_addr,
+                error,
+            } => {
+                self.publish(NetworkEvent::IncomingConnectionFailed {
+                    local_address: local_addr,
+                    remote_address: send_back_addr,
+                    message: error.to_string(),
+                });
+            }
             SwarmEvent::Dialing(_) => {
                 // eprintln!("Dialing {peer_id}");
             }
-            e => panic!("{e:?}"),
+            // We don't ban peers.
+            SwarmEvent::BannedPeer { .. } => {}
         }
     }
 
@@ -329,63 +1520,564 @@ impl EventLoop {
                     return;
                 }
 
-                if let hash_map::Entry::Vacant(e) = self.pending_dial.entry(peer_id) {
-                    self.swarm
-                        .behaviour_mut()
-                        .zinnia
-                        .add_address(&peer_id, peer_addr.clone());
+                match self.pending_dial.entry(peer_id) {
+                    hash_map::Entry::Vacant(e) => {
+                        self.swarm
+                            .behaviour_mut()
+                            .zinnia
+                            .add_address(&peer_id, peer_addr.clone());
 
-                    match self
-                        .swarm
-                        .dial(peer_addr.with(Protocol::P2p(peer_id.into())))
-                    {
-                        Ok(()) => {
-                            e.insert(sender);
+                        // The circuit addresses reported by relays end with the peer ID.
+                        let peer_addr = match peer_addr.iter().last() {
+                            Some(Protocol::P2p(hash)) if hash == peer_id.into() => peer_addr,
+                            _ => peer_addr.with(Protocol::P2p(peer_id.into())),
+                        };
+                        match self.swarm.dial(peer_addr) {
+                            Ok(()) => {
+                                e.insert(vec![sender]);
+                            }
+                            Err(err) => {
+                                let _ = sender.send(Err(PeerNodeError::dial(peer_id, &err)));
+                            }
                         }
-                        Err(err) => {
-                            let _ = sender.send(Err(Box::new(err)));
+                    }
+                    hash_map::Entry::Occupied(mut e) => {
+                        // Wait for the dial in progress, the caller gets the same result.
+                        e.get_mut().push(sender);
+                    }
+                }
+            }
+
+            Command::DialPeer { peer_id, sender } => {
+                if self.swarm.is_connected(&peer_id) {
+                    let _ = sender.send(Ok(()));
+                    return;
+                }
+
+                match self.pending_dial.entry(peer_id) {
+                    hash_map::Entry::Vacant(e) => {
+                        e.insert(vec![sender]);
+                        let known = !self
+                            .swarm
+                            .behaviour_mut()
+                            .addresses_of_peer(&peer_id)
+                            .is_empty();
+                        match self.swarm.behaviour_mut().kademlia.as_mut() {
+                            Some(kademlia) if !known => {
+                                let query_id = kademlia.get_closest_peers(peer_id);
+                                self.pending_lookups.insert(query_id, peer_id);
+                            }
+                            _ => self.dial_known_addresses(peer_id),
                         }
                     }
-                } else {
-                    todo!("Already dialing peer.");
+                    hash_map::Entry::Occupied(mut e) => {
+                        // Wait for the dial in progress, the caller gets the same result.
+                        e.get_mut().push(sender);
+                    }
                 }
             }
 
             Command::Request {
                 peer_id,
-                protocol,
+                peer_addr,
+                protocols,
                 payload,
+                options,
+                sender,
+            } => {
+                // Any of the protocols may be negotiated, a payload its codec cannot write
+                // would fail the whole connection.
+                let zinnia = &self.swarm.behaviour().zinnia;
+                for protocol in &protocols {
+                    if let Err(err) = zinnia.codec(protocol).check_payload(&payload) {
+                        let _ = sender.send(Err(PeerNodeError::invalid_payload(protocol, &err)));
+                        return;
+                    }
+                }
+                // Without retries, the peer was dialed already. With retries, the behaviour
+                // dials the peer whenever it's not connected.
+                self.swarm
+                    .behaviour_mut()
+                    .zinnia
+                    .add_address(&peer_id, peer_addr);
+                if let Some(deadline) = options.deadline {
+                    self.request_deadlines
+                        .push(tokio::time::sleep_until(deadline.into()).boxed());
+                }
+                let pending_request = PendingRequest {
+                    peer_id,
+                    protocols,
+                    payload: options.retry.as_ref().map(|_| payload.clone()),
+                    options,
+                    attempts: 0,
+                    dial_error: None,
+                    sender,
+                };
+                self.send_request(pending_request, payload);
+            }
+
+            Command::Listen { addr, sender } => match self.swarm.listen_on(addr.clone()) {
+                Ok(listener_id) => {
+                    let (events_sender, events) = mpsc::unbounded_channel();
+                    self.listeners.insert(listener_id, events_sender);
+                    self.pending_listeners.insert(
+                        listener_id,
+                        PendingListener {
+                            address: addr,
+                            sender,
+                            events,
+                        },
+                    );
+                }
+                Err(err) => {
+                    let err = match err {
+                        TransportError::MultiaddrNotSupported(_) => io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "The address is not supported.",
+                        ),
+                        TransportError::Other(err) => err,
+                    };
+                    let _ = sender.send(Err(PeerNodeError::listen(addr, &err)));
+                }
+            },
+
+            Command::ListenAddrs { sender } => {
+                let _ = sender.send(self.swarm.listeners().cloned().collect());
+            }
+
+            Command::RegisterProtocol { protocol, sender } => {
+                self.swarm
+                    .behaviour_mut()
+                    .zinnia
+                    .register_protocol(protocol.clone());
+                self.inbound_handlers.insert(protocol, sender);
+            }
+
+            Command::Ping { peer_id, sender } => {
+                if !self.swarm.is_connected(&peer_id) {
+                    // The connection was closed right after dialing.
+                    let _ = sender.send(Err(PeerNodeError::ConnectionClosed {
+                        peer_id,
+                        protocol: String::from_utf8_lossy(ping::PROTOCOL_NAME).into_owned(),
+                    }));
+                    return;
+                }
+                if self.ping_unsupported.contains(&peer_id) {
+                    let failure = ping::Failure::Unsupported;
+                    let _ = sender.send(Err(PeerNodeError::ping(peer_id, &failure)));
+                    return;
+                }
+                let ping_id = self.swarm.behaviour_mut().ping.ping(peer_id);
+                self.pending_pings
+                    .entry(peer_id)
+                    .or_default()
+                    .insert(ping_id, sender);
+            }
+
+            Command::PeerInfo { peer_id, sender } => {
+                let _ = sender.send(self.peer_infos.get(&peer_id).cloned());
+            }
+
+            Command::StartProviding { key, sender } => {
+                let result = self.kademlia().and_then(|kademlia| {
+                    kademlia
+                        .start_providing(Key::from(key))
+                        .map_err(|err| PeerNodeError::Dht {
+                            message: err.to_string(),
+                        })
+                });
+                match result {
+                    Ok(query_id) => {
+                        self.pending_provides.insert(query_id, sender);
+                    }
+                    Err(err) => {
+                        let _ = sender.send(Err(err));
+                    }
+                }
+            }
+
+            Command::StopProviding { key, sender } => {
+                let result = self
+                    .kademlia()
+                    .map(|kademlia| kademlia.stop_providing(&Key::from(key)));
+                let _ = sender.send(result);
+            }
+
+            Command::FindProviders { key, limit, sender } => {
+                let kademlia = match self.kademlia() {
+                    Ok(kademlia) => kademlia,
+                    Err(err) => {
+                        let _ = sender.send(Err(err));
+                        return;
+                    }
+                };
+                let (providers_sender, providers) = mpsc::unbounded_channel();
+                if limit > 0 {
+                    let query_id = kademlia.get_providers(Key::from(key));
+                    self.provider_searches.insert(
+                        query_id,
+                        ProviderSearch {
+                            sender: providers_sender,
+                            found: HashSet::new(),
+                            limit,
+                        },
+                    );
+                }
+                let _ = sender.send(Ok(providers));
+            }
+
+            Command::RelayStats { sender } => {
+                let _ = sender.send(self.relay_stats.clone());
+            }
+
+            Command::SetCodec { protocol, codec } => {
+                self.swarm.behaviour_mut().zinnia.set_codec(protocol, codec);
+            }
+
+            Command::Codecs { protocols, sender } => {
+                let zinnia = &self.swarm.behaviour().zinnia;
+                let _ = sender.send(protocols.iter().map(|p| zinnia.codec(p)).collect());
+            }
+
+            Command::OpenStream {
+                peer_id,
+                protocol,
                 sender,
             } => {
-                let request_id =
-                    self.swarm
-                        .behaviour_mut()
-                        .zinnia
-                        .send_request(&peer_id, &[protocol], payload);
-                self.pending_requests
-                    .insert(request_id, PendingRequest { sender });
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .zinnia
+                    .open_stream(&peer_id, std::slice::from_ref(&protocol));
+                self.pending_streams
+                    .insert(request_id, PendingStream { protocol, sender });
             }
 
+            Command::WriteStream {
+                stream_id,
+                data,
+                sender,
+            } => self.spawn_stream_op(
+                stream_id,
+                |stream| stream.writer.clone(),
+                sender,
+                |mut io| async move {
+                    io.write_all(&data).await?;
+                    io.flush().await
+                },
+            ),
+
+            Command::CloseStreamWriter { stream_id, sender } => self.spawn_stream_op(
+                stream_id,
+                |stream| stream.writer.clone(),
+                sender,
+                |mut io| async move { io.close().await },
+            ),
+
+            Command::ReadStream {
+                stream_id,
+                max_len,
+                sender,
+            } => self.spawn_stream_op(
+                stream_id,
+                |stream| stream.reader.clone(),
+                sender,
+                move |mut io| async move {
+                    let mut buf = vec![0; max_len];
+                    let len = io.read(&mut buf).await?;
+                    buf.truncate(len);
+                    Ok(buf)
+                },
+            ),
+
             Command::Shutdown => {
-                // println!("shutting down the event loop");
+                // Reject new commands, the event loop shuts down once the commands sent
+                // so far were handled.
                 self.command_receiver.close();
             }
         }
     }
+
+    fn handle_dht_event(&mut self, event: KademliaEvent) {
+        match event {
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetClosestPeers(_),
+                ..
+            } => {
+                let peer_id = match self.pending_lookups.remove(&id) {
+                    Some(peer_id) => peer_id,
+                    None => return,
+                };
+                // The lookup connects to the peer when it finds it, which completes the dial.
+                if !self.pending_dial.contains_key(&peer_id) {
+                    return;
+                }
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .addresses_of_peer(&peer_id)
+                    .is_empty()
+                {
+                    let error = PeerNodeError::Dial(Box::new(DialFailure {
+                        peer_id,
+                        protocol: None,
+                        addresses: Vec::new(),
+                        message: "The peer was not found in the DHT".into(),
+                    }));
+                    for sender in self.pending_dial.remove(&peer_id).unwrap_or_default() {
+                        let _ = sender.send(Err(error.clone()));
+                    }
+                } else {
+                    self.dial_known_addresses(peer_id);
+                }
+            }
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::StartProviding(result),
+                ..
+            } => {
+                if let Some(sender) = self.pending_provides.remove(&id) {
+                    let result = result.map(|_| ()).map_err(|err| match err {
+                        AddProviderError::Timeout { .. } => PeerNodeError::Dht {
+                            message: "Timed out publishing the provider record".into(),
+                        },
+                    });
+                    let _ = sender.send(result);
+                }
+            }
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetProviders(result),
+                ..
+            } => {
+                let search = match self.provider_searches.get_mut(&id) {
+                    Some(search) => search,
+                    None => return,
+                };
+                let providers = match result {
+                    Ok(GetProvidersOk::FoundProviders { providers, .. }) => providers,
+                    // The lookup finished, either with or without reaching the closest peers.
+                    _ => {
+                        self.provider_searches.remove(&id);
+                        return;
+                    }
+                };
+                let mut finished = false;
+                for peer_id in providers {
+                    if !search.found.insert(peer_id) {
+                        continue;
+                    }
+                    let provider = Provider {
+                        peer_id,
+                        addresses: self.swarm.behaviour_mut().addresses_of_peer(&peer_id),
+                    };
+                    // The receiver may have been dropped, nobody is interested in more providers.
+                    if search.sender.send(provider).is_err() || search.found.len() >= search.limit {
+                        finished = true;
+                        break;
+                    }
+                }
+                if finished {
+                    self.provider_searches.remove(&id);
+                    if let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                        if let Some(mut query) = kademlia.query_mut(&id) {
+                            query.finish();
+                        }
+                    }
+                }
+            }
+            // Bootstrapping, republishing and updates of the routing table need no action.
+            _ => {}
+        }
+    }
+
+    fn kademlia(&mut self) -> Result<&mut Dht, PeerNodeError> {
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .as_mut()
+            .ok_or(PeerNodeError::DhtDisabled)
+    }
+
+    /// Makes the peer reachable through our DHT when it takes part in the DHT.
+    fn add_dht_addresses(&mut self, peer_id: PeerId, info: &PeerInfo) {
+        let kademlia = match self.swarm.behaviour_mut().kademlia.as_mut() {
+            Some(kademlia) => kademlia,
+            None => return,
+        };
+        let in_dht = kademlia.protocol_names().iter().any(|name| {
+            info.protocols
+                .iter()
+                .any(|protocol| protocol.as_bytes() == name.as_ref())
+        });
+        if in_dht {
+            for addr in &info.listen_addrs {
+                kademlia.add_address(&peer_id, addr.clone());
+            }
+        }
+    }
+
+    /// Dials the peer in `pending_dial` at the addresses known by the behaviours.
+    fn dial_known_addresses(&mut self, peer_id: PeerId) {
+        if let Err(err) = self.swarm.dial(DialOpts::peer_id(peer_id).build()) {
+            let error = PeerNodeError::dial(peer_id, &err);
+            for sender in self.pending_dial.remove(&peer_id).unwrap_or_default() {
+                let _ = sender.send(Err(error.clone()));
+            }
+        }
+    }
+
+    fn send_request(&mut self, mut pending_request: PendingRequest, payload: RequestPayload) {
+        let request_id = self.swarm.behaviour_mut().zinnia.send_request(
+            &pending_request.peer_id,
+            &pending_request.protocols,
+            payload,
+            pending_request.options.clone(),
+        );
+        pending_request.attempts += 1;
+        self.pending_requests.insert(request_id, pending_request);
+    }
+
+    /// Sends the request again once its backoff elapsed, see [`RetryPolicy`].
+    fn retry_request(&mut self, request_id: RequestId) {
+        if let Some(pending_request) = self.waiting_retries.remove(&request_id) {
+            let payload = pending_request
+                .payload
+                .clone()
+                .expect("Retried request should keep its payload.");
+            self.send_request(pending_request, payload);
+        }
+    }
+
+    /// Fails the requests whose deadline passed, the callers have given up on them already.
+    ///
+    /// Requests still waiting for a connection are dropped, the substreams of the requests
+    /// sent already are closed once their deadline passes, see [`RequestOptions::deadline`].
+    fn expire_requests(&mut self) {
+        let now = Instant::now();
+        let expired = |pending: &PendingRequest| matches!(pending.options.deadline, Some(deadline) if deadline <= now);
+        let expired_requests: Vec<RequestId> = self
+            .pending_requests
+            .iter()
+            .filter(|(_, pending)| expired(pending))
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        for request_id in expired_requests {
+            let pending_request = self
+                .pending_requests
+                .remove(&request_id)
+                .expect("Expired request should be pending.");
+            self.swarm
+                .behaviour_mut()
+                .zinnia
+                .cancel_pending_request(&pending_request.peer_id, request_id);
+            pending_request.fail(OutboundFailure::Timeout);
+        }
+        // The retry timers of these requests find nothing to retry.
+        let expired_retries: Vec<RequestId> = self
+            .waiting_retries
+            .iter()
+            .filter(|(_, pending)| expired(pending))
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        for request_id in expired_retries {
+            let pending_request = self
+                .waiting_retries
+                .remove(&request_id)
+                .expect("Expired request should wait for a retry.");
+            pending_request.fail(OutboundFailure::Timeout);
+        }
+    }
+
+    /// Requests a reservation on the relay by listening on its circuit address.
+    fn reserve(&mut self, relay_peer_id: PeerId, relay_addr: Multiaddr) {
+        let addr = relay::circuit_addr(relay_peer_id, relay_addr.clone());
+        match self.swarm.listen_on(addr) {
+            Ok(listener_id) => {
+                self.reservations
+                    .insert(listener_id, (relay_peer_id, relay_addr));
+            }
+            Err(err) => self.reservation_lost(relay_peer_id, relay_addr, err.to_string()),
+        }
+    }
+
+    /// Reports the lost reservation and requests it again after [`RESERVATION_RETRY_DELAY`].
+    fn reservation_lost(&mut self, relay_peer_id: PeerId, relay_addr: Multiaddr, message: String) {
+        self.publish(NetworkEvent::ReservationLost {
+            relay_peer_id,
+            message,
+        });
+        self.reservation_timers.push(
+            tokio::time::sleep(RESERVATION_RETRY_DELAY)
+                .map(move |()| (relay_peer_id, relay_addr))
+                .boxed(),
+        );
+    }
+
+    fn publish(&self, event: NetworkEvent) {
+        // There may be no subscribers, that's fine.
+        let _ = self.event_sender.send(event);
+    }
+
+    fn notify_listener(&mut self, listener_id: ListenerId, event: ListenerEvent) {
+        if let Some(events) = self.listeners.get(&listener_id) {
+            // The `Listener` may have been dropped already, that's fine.
+            let _ = events.send(event);
+        }
+    }
+
+    /// Runs an operation on the given half of the given stream in a new task and sends
+    /// the result to `sender`.
+    fn spawn_stream_op<S, T, F, Fut>(
+        &self,
+        stream_id: RequestId,
+        half: impl FnOnce(&OpenStream) -> Arc<Mutex<S>>,
+        sender: oneshot::Sender<io::Result<T>>,
+        op: F,
+    ) where
+        S: Send + 'static,
+        T: Send + 'static,
+        F: FnOnce(OwnedMutexGuard<S>) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+    {
+        let substream = match self.streams.get(&stream_id) {
+            Some(stream) => half(stream),
+            None => {
+                let err = io::Error::new(io::ErrorKind::NotConnected, "The stream was closed.");
+                let _ = sender.send(Err(err));
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            let result = op(substream.lock_owned().await).await;
+            let _ = sender.send(result);
+        });
+    }
 }
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ComposedEvent")]
 struct ComposedBehaviour {
     pub zinnia: RequestResponse,
-    // We can add more behaviours later.
-    // request_response: request_response::Behaviour<FileExchangeCodec>,
+    pub ping: ping::Behaviour,
+    pub identify: identify::Behaviour,
+    pub kademlia: Toggle<Dht>,
+    pub relay_client: relay_client::Client,
+    pub relay_server: Toggle<relay_server::Relay>,
 }
 
 #[derive(Debug)]
 enum ComposedEvent {
     Zinnia(RequestResponseEvent),
-    // We can add more events later.
+    Ping(ping::Event),
+    Identify(identify::Event),
+    Kademlia(KademliaEvent),
+    /// Relayed connections are reported like any other connections, the details of
+    /// the reservations and circuits are not needed.
+    RelayClient,
+    RelayServer(relay_server::Event),
 }
 
 impl From<RequestResponseEvent> for ComposedEvent {
@@ -394,57 +2086,2127 @@ impl From<RequestResponseEvent> for ComposedEvent {
     }
 }
 
+impl From<ping::Event> for ComposedEvent {
+    fn from(event: ping::Event) -> Self {
+        ComposedEvent::Ping(event)
+    }
+}
+
+impl From<identify::Event> for ComposedEvent {
+    fn from(event: identify::Event) -> Self {
+        ComposedEvent::Identify(event)
+    }
+}
+
+impl From<KademliaEvent> for ComposedEvent {
+    fn from(event: KademliaEvent) -> Self {
+        ComposedEvent::Kademlia(event)
+    }
+}
+
+impl From<relay_client::Event> for ComposedEvent {
+    fn from(_: relay_client::Event) -> Self {
+        ComposedEvent::RelayClient
+    }
+}
+
+impl From<relay_server::Event> for ComposedEvent {
+    fn from(event: relay_server::Event) -> Self {
+        ComposedEvent::RelayServer(event)
+    }
+}
+
 #[derive(Debug)]
 enum Command {
     Dial {
         peer_id: PeerId,
         peer_addr: Multiaddr,
-        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+        sender: oneshot::Sender<Result<(), PeerNodeError>>,
+    },
+    DialPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Result<(), PeerNodeError>>,
     },
     Request {
         peer_id: PeerId,
-        protocol: ProtocolInfo,
+        peer_addr: Multiaddr,
+        protocols: Vec<ProtocolInfo>,
         payload: RequestPayload,
-        sender: oneshot::Sender<Result<ResponsePayload, Box<dyn Error + Send>>>,
+        options: RequestOptions,
+        sender: oneshot::Sender<Result<Response, PeerNodeError>>,
     },
-    Shutdown,
-}
-
-#[cfg(test)]
-mod tests {
-    use libp2p::swarm::DialError;
-    use libp2p::TransportError;
-    use std::time::Duration;
-    use tokio_util::sync::CancellationToken;
-
-    use super::*;
-
-    const DEFAULT_TEST_CONFIG: PeerNodeConfig = PeerNodeConfig {
-        connection_keep_alive: Duration::from_secs(1),
-        request_timeout: Duration::from_secs(1),
-    };
-
-    #[tokio::test]
-    async fn requests_ping_protocol() {
-        let cancellation_token = CancellationToken::new();
-
-        let server_id_keys = identity::Keypair::generate_ed25519();
+    Listen {
+        addr: Multiaddr,
+        sender: oneshot::Sender<Result<Listener, PeerNodeError>>,
+    },
+    ListenAddrs {
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    RegisterProtocol {
+        protocol: ProtocolInfo,
+        sender: mpsc::Sender<InboundRequest>,
+    },
+    PeerInfo {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Option<PeerInfo>>,
+    },
+    StartProviding {
+        key: Vec<u8>,
+        sender: oneshot::Sender<Result<(), PeerNodeError>>,
+    },
+    StopProviding {
+        key: Vec<u8>,
+        sender: oneshot::Sender<Result<(), PeerNodeError>>,
+    },
+    FindProviders {
+        key: Vec<u8>,
+        limit: usize,
+        sender: oneshot::Sender<Result<mpsc::UnboundedReceiver<Provider>, PeerNodeError>>,
+    },
+    RelayStats {
+        sender: oneshot::Sender<Option<RelayStats>>,
+    },
+    SetCodec {
+        protocol: ProtocolInfo,
+        codec: Arc<dyn Codec>,
+    },
+    Codecs {
+        protocols: Vec<ProtocolInfo>,
+        sender: oneshot::Sender<Vec<Arc<dyn Codec>>>,
+    },
+    Ping {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Result<Duration, PeerNodeError>>,
+    },
+    OpenStream {
+        peer_id: PeerId,
+        protocol: ProtocolInfo,
+        sender: oneshot::Sender<Result<StreamHandle, PeerNodeError>>,
+    },
+    WriteStream {
+        stream_id: RequestId,
+        data: Vec<u8>,
+        sender: oneshot::Sender<io::Result<()>>,
+    },
+    CloseStreamWriter {
+        stream_id: RequestId,
+        sender: oneshot::Sender<io::Result<()>>,
+    },
+    ReadStream {
+        stream_id: RequestId,
+        max_len: usize,
+        sender: oneshot::Sender<io::Result<Vec<u8>>>,
+    },
+    Shutdown,
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+
+    const TEST_REQUEST_RESPONSE_CONFIG: RequestResponseConfig = RequestResponseConfig {
+        connection_keep_alive: Duration::from_secs(1),
+        request_timeout: Duration::from_secs(1),
+        max_response_size: 1024,
+    };
+
+    fn default_test_config() -> PeerNodeConfig {
+        PeerNodeConfig {
+            request_response: TEST_REQUEST_RESPONSE_CONFIG,
+            shutdown_timeout: Duration::from_millis(200),
+            ping: PingConfig {
+                interval: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A protocol whose requests are echoed back by [`spawn_echo_server`].
+    const ECHO_PROTOCOL: &[u8] = b"/zinnia/echo/1.0.0";
+    /// A protocol whose requests the tests hold on to without responding.
+    const SLOW_PROTOCOL: &[u8] = b"/zinnia/slow/1.0.0";
+
+    /// Starts a node listening on an ephemeral port and accepting requests for the given
+    /// protocol.
+    async fn spawn_server(
+        protocol: &[u8],
+    ) -> (PeerNode, Multiaddr, mpsc::Receiver<InboundRequest>) {
+        let server = PeerNode::spawn(default_test_config()).unwrap();
+        let listener = server
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let requests = server.register_protocol(protocol).await;
+        (server, listener.address().clone(), requests)
+    }
+
+    /// Starts a node echoing the requests for [`ECHO_PROTOCOL`], see [`spawn_server`].
+    /// The echo task finishes once the node is shut down.
+    async fn spawn_echo_server() -> (PeerNode, Multiaddr, JoinHandle<()>) {
+        let (server, server_addr, requests) = spawn_server(ECHO_PROTOCOL).await;
+        (server, server_addr, spawn_echo(requests))
+    }
+
+    /// Responds to the given requests with their payload.
+    fn spawn_echo(mut requests: mpsc::Receiver<InboundRequest>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                let _ = request.responder.respond(request.payload);
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn requests_ping_protocol() {
+        let (server_peer_id, server_addr, cancellation_token, server_task) =
+            spawn_ping_server().await;
+
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        peer.dial(server_peer_id, server_addr.clone())
+            .await
+            .expect("Should be able to dial a remote peer.");
+
+        // Ping echoes the payload and keeps the stream open, the response has a fixed size.
+        peer.set_codec(
+            libp2p::ping::PROTOCOL_NAME,
+            FixedLength {
+                size: crate::ping::PING_SIZE,
+            },
+        )
+        .await;
+        let request = crate::ping::new_request_payload();
+        let response = peer
+            .request_protocol(
+                server_peer_id,
+                server_addr.clone(),
+                libp2p::ping::PROTOCOL_NAME,
+                request.clone(),
+            )
+            .await
+            .expect("Should be able to send PING request");
+        assert_eq!(response, request, "PING response should match the request");
+
+        let rtt = peer
+            .ping(server_peer_id, server_addr)
+            .await
+            .expect("Should be able to ping the server");
+        assert!(rtt < Duration::from_secs(1));
+
+        cancellation_token.cancel();
+        let _ = server_task.await;
+    }
+
+    #[tokio::test]
+    async fn pings_on_demand() {
+        let (server_peer_id, server_addr, cancellation_token, server_task) =
+            spawn_ping_server().await;
+
+        // The background pings are too rare to answer the calls.
+        let peer = PeerNode::spawn(PeerNodeConfig {
+            ping: PingConfig {
+                interval: Duration::from_secs(60),
+                ..Default::default()
+            },
+            ping_timeout: Duration::from_secs(5),
+            ..default_test_config()
+        })
+        .unwrap();
+        let started = std::time::Instant::now();
+        for _ in 0..3 {
+            let rtt = peer
+                .ping(server_peer_id, server_addr.clone())
+                .await
+                .expect("Should be able to ping the server");
+            assert!(rtt < Duration::from_secs(1));
+        }
+        // Concurrent calls share the stream, each gets a ping of its own.
+        let (first, second) = tokio::join!(
+            peer.ping(server_peer_id, server_addr.clone()),
+            peer.ping(server_peer_id, server_addr.clone())
+        );
+        first.expect("The first concurrent ping should succeed");
+        second.expect("The second concurrent ping should succeed");
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "The pings should be sent right away"
+        );
+
+        peer.shutdown().await.unwrap();
+        cancellation_token.cancel();
+        let _ = server_task.await;
+    }
+
+    #[tokio::test]
+    async fn fails_fast_pinging_peers_without_ping() {
+        // The relay server doesn't speak the ping protocol.
+        let (relay_peer_id, relay_addr, cancellation_token, relay_task) =
+            spawn_relay_server().await;
+
+        let peer = PeerNode::spawn(PeerNodeConfig {
+            ping_timeout: Duration::from_secs(2),
+            ..default_test_config()
+        })
+        .unwrap();
+        // The background pings stop after the first failure, later calls must not wait for
+        // the next ping.
+        for _ in 0..2 {
+            let err = peer
+                .ping(relay_peer_id, relay_addr.clone())
+                .await
+                .expect_err("The relay doesn't support ping");
+            assert_eq!(err.code(), "ERR_UNSUPPORTED_PROTOCOLS");
+        }
+
+        peer.shutdown().await.unwrap();
+        cancellation_token.cancel();
+        let _ = relay_task.await;
+    }
+
+    #[tokio::test]
+    async fn monitors_ping_round_trip_times() {
+        let (server_peer_id, server_addr, cancellation_token, server_task) =
+            spawn_ping_server().await;
+        let unreachable_peer_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+
+        // The monitor sends pings of its own, the background pings are too rare to matter.
+        let peer = PeerNode::spawn(PeerNodeConfig {
+            ping: PingConfig {
+                interval: Duration::from_secs(60),
+                ..Default::default()
+            },
+            ..default_test_config()
+        })
+        .unwrap();
+        let mut events = peer.subscribe();
+        let monitor = peer
+            .monitor_pings(
+                vec![
+                    (server_peer_id, server_addr),
+                    (
+                        unreachable_peer_id,
+                        "/ip4/127.0.0.1/tcp/10".parse().unwrap(),
+                    ),
+                ],
+                PingMonitorConfig {
+                    interval: Duration::from_millis(100),
+                    timeout: Duration::from_secs(1),
+                    window: 2,
+                },
+            )
+            .unwrap();
+
+        let server_rounds = async {
+            let mut server_rounds = 0;
+            while server_rounds < 3 {
+                match events.recv().await.unwrap() {
+                    NetworkEvent::PingStats(stats) if stats.peer_id == server_peer_id => {
+                        server_rounds += 1;
+                        assert_eq!(stats.lost, 0);
+                        assert!(stats.last_rtt.is_some());
+                    }
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), server_rounds)
+            .await
+            .expect("The monitor should ping every interval");
+
+        let stats = monitor.stats(&server_peer_id).unwrap();
+        assert!(stats.sent >= 3);
+        let rtt = stats.rtt.expect("The server should answer the pings");
+        assert_eq!(rtt.samples, 2, "Only the latest samples should be kept");
+        assert!(rtt.min <= rtt.p50 && rtt.p50 <= rtt.p99 && rtt.p99 <= rtt.max);
+
+        let stats = monitor.stats(&unreachable_peer_id).unwrap();
+        assert_eq!(stats.sent, stats.lost);
+        assert_eq!(stats.rtt, None);
+        assert_eq!(monitor.all_stats().len(), 2);
+
+        drop(monitor);
+        peer.shutdown().await.unwrap();
+        cancellation_token.cancel();
+        let _ = server_task.await;
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_ping_monitor_configs() {
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let peers = vec![(peer.peer_id(), "/ip4/127.0.0.1/tcp/10".parse().unwrap())];
+        for config in [
+            PingMonitorConfig {
+                interval: Duration::ZERO,
+                ..Default::default()
+            },
+            PingMonitorConfig {
+                timeout: Duration::ZERO,
+                ..Default::default()
+            },
+            PingMonitorConfig {
+                window: 0,
+                ..Default::default()
+            },
+        ] {
+            let err = peer
+                .monitor_pings(peers.clone(), config)
+                .expect_err("The config should be rejected");
+            assert_eq!(err.code(), "ERR_INVALID_CONFIG");
+        }
+        peer.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exchanges_ping_over_stream() {
+        let (server_peer_id, server_addr, cancellation_token, server_task) =
+            spawn_ping_server().await;
+
+        // The server keeps a single inbound ping stream per connection. Open the stream of
+        // the background pings first, and don't send another ping in the background, which
+        // would open it again after losing it to our stream, replacing our stream.
+        let peer = PeerNode::spawn(PeerNodeConfig {
+            ping: PingConfig {
+                interval: Duration::from_secs(60),
+                ..Default::default()
+            },
+            ..default_test_config()
+        })
+        .unwrap();
+        peer.ping(server_peer_id, server_addr.clone())
+            .await
+            .expect("Should be able to ping the server");
+        let stream = peer
+            .dial_protocol(server_peer_id, server_addr, libp2p::ping::PROTOCOL_NAME)
+            .await
+            .expect("Should be able to open a PING stream");
+        assert_eq!(stream.peer_id(), server_peer_id);
+
+        // An empty buffer would make the read indistinguishable from the end of the stream.
+        let err = peer.read(&stream, &mut []).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                PeerNodeError::Stream(failure) if failure.kind == io::ErrorKind::InvalidInput
+            ),
+            "Unexpected error: {err:?}"
+        );
+
+        // Ping echoes the payload and keeps the stream open, we must read exactly 32 bytes.
+        // Start reading before writing, a pending read must not block the write.
+        let request = crate::ping::new_request_payload();
+        let read_response = async {
+            let mut response = vec![0; request.len()];
+            let mut received = 0;
+            while received < response.len() {
+                let len = peer
+                    .read(&stream, &mut response[received..])
+                    .await
+                    .expect("Should be able to read PING response");
+                assert_ne!(
+                    len, 0,
+                    "The stream should not end before the whole payload is read"
+                );
+                received += len;
+            }
+            response
+        };
+        let write_request = async {
+            peer.write_all(&stream, &request)
+                .await
+                .expect("Should be able to write PING payload");
+        };
+        let (response, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(read_response, write_request)
+        })
+        .await
+        .expect("Reading and writing should not block each other");
+        assert_eq!(response, request, "PING response should match the request");
+
+        peer.close_writer(&stream)
+            .await
+            .expect("Should be able to close the writer");
+        drop(stream);
+
+        cancellation_token.cancel();
+        let _ = server_task.await;
+    }
+
+    #[tokio::test]
+    async fn answers_inbound_requests() {
+        const UNKNOWN_PROTOCOL: &[u8] = b"/zinnia/unknown/1.0.0";
+
+        let server_id_keys = identity::Keypair::generate_ed25519();
         let server_peer_id = server_id_keys.public().to_peer_id();
-        let mut server_transport = create_transport(&server_id_keys).unwrap();
+        let mut server_swarm = Swarm::with_tokio_executor(
+            create_transport(&server_id_keys).unwrap(),
+            RequestResponse::new(TEST_REQUEST_RESPONSE_CONFIG),
+            server_peer_id,
+        );
+        let server_addr = listen_on_ephemeral_port(&mut server_swarm).await;
+
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let mut requests = peer.register_protocol(ECHO_PROTOCOL).await;
+        let echo_task = tokio::spawn(async move {
+            let request = requests.recv().await.expect("Should receive a request");
+            assert_eq!(request.peer_id, server_peer_id);
+            assert_eq!(request.protocol, ECHO_PROTOCOL);
+            request
+                .responder
+                .respond(request.payload)
+                .expect("Should be able to send the response");
+        });
+
+        // The remote peer sends requests back over the connection we open.
+        let dial_task = tokio::spawn(async move {
+            peer.dial(server_peer_id, server_addr)
+                .await
+                .expect("Should be able to dial a remote peer.");
+            peer
+        });
+
+        let mut echo_request_id = None;
+        let mut unknown_request_id = None;
+        let mut echo_response = None;
+        let mut unknown_failure = None;
+        while echo_response.is_none() || unknown_failure.is_none() {
+            match server_swarm.select_next_some().await {
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    let zinnia = server_swarm.behaviour_mut();
+                    echo_request_id = Some(zinnia.send_request(
+                        &peer_id,
+                        &[ECHO_PROTOCOL.into()],
+                        b"hi".to_vec(),
+                        Default::default(),
+                    ));
+                    unknown_request_id = Some(zinnia.send_request(
+                        &peer_id,
+                        &[UNKNOWN_PROTOCOL.into()],
+                        vec![],
+                        Default::default(),
+                    ));
+                }
+                SwarmEvent::Behaviour(RequestResponseEvent::Message {
+                    message:
+                        RequestResponseMessage::Response {
+                            request_id,
+                            response,
+                            ..
+                        },
+                    ..
+                }) => {
+                    assert_eq!(Some(request_id), echo_request_id);
+                    echo_response = Some(response);
+                }
+                SwarmEvent::Behaviour(RequestResponseEvent::OutboundFailure {
+                    request_id,
+                    error,
+                    ..
+                }) => {
+                    assert_eq!(Some(request_id), unknown_request_id);
+                    unknown_failure = Some(error);
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(echo_response.unwrap(), b"hi");
+        assert_eq!(
+            unknown_failure.unwrap(),
+            behaviour::OutboundFailure::UnsupportedProtocols
+        );
+
+        echo_task.await.unwrap();
+        let peer = dial_task.await.unwrap();
+        peer.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_closed_responders() {
+        let (server, server_addr, mut requests) = spawn_server(ECHO_PROTOCOL).await;
+
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let request_task = tokio::spawn(async move {
+            let result = peer
+                .request_protocol(server.peer_id(), server_addr, ECHO_PROTOCOL, b"hi".to_vec())
+                .await;
+            peer.shutdown().await.unwrap();
+            (server, result)
+        });
+
+        let request = requests.recv().await.expect("Should receive a request");
+        assert!(request.responder.is_open());
+
+        // The request times out on both sides while we hold the response back.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while request.responder.is_open() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("The responder should be closed after the request timed out");
+        assert_eq!(
+            request.responder.respond(b"hi".to_vec()),
+            Err(b"hi".to_vec())
+        );
+
+        let (server, result) = request_task.await.unwrap();
+        assert!(result.is_err());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_inbound_protocols() {
+        const UNKNOWN_PROTOCOL: &[u8] = b"/zinnia/unknown/1.0.0";
+        const OTHER_UNKNOWN_PROTOCOL: &[u8] = b"/zinnia/other-unknown/1.0.0";
+
+        let server_id_keys = identity::Keypair::generate_ed25519();
+        let server_peer_id = server_id_keys.public().to_peer_id();
+        let server_behaviour = RequestResponse::new(TEST_REQUEST_RESPONSE_CONFIG);
+        let rejected_protocols = server_behaviour.rejected_protocols();
+        let server_transport = Transport::map(
+            create_transport(&server_id_keys).unwrap(),
+            move |(peer_id, muxer), endpoint| {
+                let muxer = RecordingMuxer::new(muxer, peer_id, endpoint, &rejected_protocols);
+                (peer_id, StreamMuxerBox::new(muxer))
+            },
+        )
+        .boxed();
+        let mut server_swarm =
+            Swarm::with_tokio_executor(server_transport, server_behaviour, server_peer_id);
+        let server_addr = listen_on_ephemeral_port(&mut server_swarm).await;
+
+        let client_id_keys = identity::Keypair::generate_ed25519();
+        let client_peer_id = client_id_keys.public().to_peer_id();
+        let mut client_swarm = Swarm::with_tokio_executor(
+            create_transport(&client_id_keys).unwrap(),
+            RequestResponse::new(TEST_REQUEST_RESPONSE_CONFIG),
+            client_peer_id,
+        );
+        client_swarm
+            .behaviour_mut()
+            .add_address(&server_peer_id, server_addr);
+        // Concurrent requests over the same connection, each failure reports the protocol
+        // of its own request.
+        let mut protocols = HashMap::new();
+        for protocol in [UNKNOWN_PROTOCOL, OTHER_UNKNOWN_PROTOCOL] {
+            let request_id = client_swarm.behaviour_mut().send_request(
+                &server_peer_id,
+                &[protocol.into()],
+                vec![],
+                Default::default(),
+            );
+            protocols.insert(request_id, protocol);
+        }
+        let client_task = tokio::spawn(async move {
+            loop {
+                if let SwarmEvent::Behaviour(RequestResponseEvent::OutboundFailure {
+                    request_id,
+                    ..
+                }) = client_swarm.select_next_some().await
+                {
+                    // Yamux resets the substream dropped after the failed negotiation only
+                    // when the connection makes progress, e.g. opens another substream.
+                    let protocol = protocols.remove(&request_id).unwrap();
+                    let request_id = client_swarm.behaviour_mut().send_request(
+                        &server_peer_id,
+                        &[protocol.into()],
+                        vec![],
+                        Default::default(),
+                    );
+                    protocols.insert(request_id, protocol);
+                }
+            }
+        });
+
+        let mut reported = HashSet::new();
+        while reported.len() < 2 {
+            if let SwarmEvent::Behaviour(RequestResponseEvent::InboundFailure {
+                peer, error, ..
+            }) = server_swarm.select_next_some().await
+            {
+                assert_eq!(peer, client_peer_id);
+                let requested = match &error {
+                    behaviour::InboundFailure::UnsupportedProtocols { requested } => {
+                        requested.clone()
+                    }
+                    error => panic!("Unexpected inbound failure: {error}"),
+                };
+                assert_eq!(requested.len(), 1, "Unexpected protocols: {requested:?}");
+                if requested[0].as_bytes() == UNKNOWN_PROTOCOL {
+                    assert_eq!(
+                        error.to_string(),
+                        "The local peer supports none of the protocols requested by the remote: /zinnia/unknown/1.0.0"
+                    );
+                }
+                reported.insert(requested[0].clone());
+            }
+        }
+        assert!(reported.contains("/zinnia/unknown/1.0.0"));
+        assert!(reported.contains("/zinnia/other-unknown/1.0.0"));
+
+        client_task.abort();
+    }
+
+    #[tokio::test]
+    async fn persists_identity_in_key_file() {
+        let key_file = std::env::temp_dir()
+            .join(format!("zinnia-test-{}", rand::random::<u64>()))
+            .join("peer.key");
+
+        let config = PeerNodeConfig {
+            identity: NodeIdentity::File(key_file.clone()),
+            ..default_test_config()
+        };
+        let first = PeerNode::spawn(config.clone()).unwrap();
+        let first_peer_id = first.peer_id();
+        first.shutdown().await.unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key_file).unwrap().permissions().mode();
+            assert_eq!(
+                mode & 0o777,
+                0o600,
+                "Key file should be readable by the owner only"
+            );
+        }
+
+        let second = PeerNode::spawn(config).unwrap();
+        assert_eq!(
+            second.peer_id(),
+            first_peer_id,
+            "Peer ID should be preserved across restarts"
+        );
+        second.shutdown().await.unwrap();
+
+        std::fs::remove_dir_all(key_file.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn listens_on_ephemeral_port() {
+        let server = PeerNode::spawn(default_test_config()).unwrap();
+        let listener = server
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .expect("Should be able to listen on an ephemeral port");
+        let server_addr = listener.address().clone();
+        assert!(
+            !server_addr.iter().any(|p| p == Protocol::Tcp(0)),
+            "The listen address should contain the port assigned by the OS: {server_addr}"
+        );
+        assert_eq!(
+            server.listen_addrs().await.unwrap(),
+            vec![server_addr.clone()]
+        );
+
+        let mut requests = server.register_protocol(ECHO_PROTOCOL).await;
+        let echo_task = tokio::spawn(async move {
+            let request = requests.recv().await.expect("Should receive a request");
+            request
+                .responder
+                .respond(request.payload)
+                .expect("Should be able to send the response");
+        });
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let response = client
+            .request_protocol(
+                server.peer_id(),
+                server_addr.clone(),
+                ECHO_PROTOCOL,
+                b"hello".to_vec(),
+            )
+            .await
+            .expect("Should be able to send a request to the listening node");
+        assert_eq!(response, b"hello");
+        echo_task.await.unwrap();
+
+        // The port is released once the node was shut down.
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        let server = PeerNode::spawn(default_test_config()).unwrap();
+        server
+            .listen_on(server_addr)
+            .await
+            .expect("Should be able to listen on the port released by the stopped node");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shares_dial_in_progress() {
+        let (server, server_addr, echo_task) = spawn_echo_server().await;
+
+        // Two handles sharing the same node, as if used from different tasks.
+        let first = PeerNode::spawn(default_test_config()).unwrap();
+        let second = first.clone();
+        let server_peer_id = server.peer_id();
+
+        // Both callers start dialing before the connection is established.
+        let (first_result, second_result) = tokio::join!(
+            first.dial(server_peer_id, server_addr.clone()),
+            second.dial(server_peer_id, server_addr.clone())
+        );
+        first_result.expect("The first dial should succeed");
+        second_result.expect("The second dial should succeed");
+
+        let (first_result, second_result) = tokio::join!(
+            first.request_protocol(
+                server_peer_id,
+                server_addr.clone(),
+                ECHO_PROTOCOL,
+                b"first".to_vec()
+            ),
+            second.request_protocol(
+                server_peer_id,
+                server_addr.clone(),
+                ECHO_PROTOCOL,
+                b"second".to_vec()
+            )
+        );
+        assert_eq!(
+            first_result.expect("The first request should succeed"),
+            b"first"
+        );
+        assert_eq!(
+            second_result.expect("The second request should succeed"),
+            b"second"
+        );
+
+        drop(second);
+        first.shutdown().await.unwrap();
+        echo_task.abort();
+    }
+
+    #[tokio::test]
+    async fn serves_requests_from_cloned_handles() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let (server, server_addr, echo_task) = spawn_echo_server().await;
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        assert_send_sync(&client);
+        let tasks: Vec<_> = (0..8u8)
+            .map(|ix| {
+                let client = client.clone();
+                let server_peer_id = server.peer_id();
+                let server_addr = server_addr.clone();
+                tokio::spawn(async move {
+                    client
+                        .request_protocol(server_peer_id, server_addr, ECHO_PROTOCOL, vec![ix])
+                        .await
+                })
+            })
+            .collect();
+        for (ix, task) in tasks.into_iter().enumerate() {
+            let response = task
+                .await
+                .unwrap()
+                .expect("Each task should receive its response");
+            assert_eq!(response, vec![ix as u8]);
+        }
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        echo_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_when_last_handle_is_dropped() {
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let event_loop_task = peer.event_loop_task.clone();
+        let other = peer.clone();
+        drop(peer);
+        // The node keeps running while a handle is alive.
+        assert_eq!(other.listen_addrs().await.unwrap(), vec![]);
+        drop(other);
+
+        let handle = event_loop_task.lock().await.take().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("The event loop should stop after the last handle was dropped")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_pending_requests_on_shutdown() {
+        let (server, server_addr, mut requests) = spawn_server(SLOW_PROTOCOL).await;
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let request_task = {
+            let client = client.clone();
+            let server_peer_id = server.peer_id();
+            let server_addr = server_addr.clone();
+            tokio::spawn(async move {
+                client
+                    .request_protocol(server_peer_id, server_addr, SLOW_PROTOCOL, vec![])
+                    .await
+            })
+        };
+
+        // Hold on to the request without responding, longer than the shutdown timeout.
+        let _request = requests.recv().await.expect("Should receive a request");
+        client.shutdown().await.unwrap();
+
+        assert_eq!(
+            request_task.await.unwrap(),
+            Err(PeerNodeError::ShuttingDown)
+        );
+        assert_eq!(
+            client.dial(server.peer_id(), server_addr).await,
+            Err(PeerNodeError::ShuttingDown)
+        );
+        assert_eq!(
+            client.listen_addrs().await,
+            Err(PeerNodeError::ShuttingDown)
+        );
+        assert!(client
+            .register_protocol(SLOW_PROTOCOL)
+            .await
+            .recv()
+            .await
+            .is_none());
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shuts_down_with_requests_in_flight_to_several_peers() {
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let mut servers = Vec::new();
+        let mut request_tasks = Vec::new();
+        let mut held_requests = Vec::new();
+        for _ in 0..2 {
+            let (server, server_addr, mut requests) = spawn_server(SLOW_PROTOCOL).await;
+
+            let client = client.clone();
+            let server_peer_id = server.peer_id();
+            request_tasks.push(tokio::spawn(async move {
+                client
+                    .request_protocol(server_peer_id, server_addr, SLOW_PROTOCOL, vec![])
+                    .await
+            }));
+            held_requests.push(requests.recv().await.expect("Should receive a request"));
+            servers.push(server);
+        }
+
+        // Closing the first connection fails the request to it again while the
+        // second connection is still being closed.
+        client.shutdown().await.unwrap();
+
+        for request_task in request_tasks {
+            assert_eq!(
+                request_task.await.unwrap(),
+                Err(PeerNodeError::ShuttingDown)
+            );
+        }
+        for server in servers {
+            server.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn drains_requests_in_flight_on_shutdown() {
+        let (server, server_addr, mut requests) = spawn_server(SLOW_PROTOCOL).await;
+
+        let client = PeerNode::spawn(PeerNodeConfig {
+            shutdown_timeout: Duration::from_secs(1),
+            ..default_test_config()
+        })
+        .unwrap();
+        let request_task = {
+            let client = client.clone();
+            let server_peer_id = server.peer_id();
+            tokio::spawn(async move {
+                client
+                    .request_protocol(server_peer_id, server_addr, SLOW_PROTOCOL, b"hi".to_vec())
+                    .await
+            })
+        };
+
+        let request = requests.recv().await.expect("Should receive a request");
+        let respond_task = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _ = request.responder.respond(request.payload);
+        });
+        client.shutdown().await.unwrap();
+
+        assert_eq!(
+            request_task
+                .await
+                .unwrap()
+                .expect("The request in flight should finish before the shutdown"),
+            b"hi"
+        );
+
+        respond_task.await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn publishes_connection_events() {
+        let server = PeerNode::spawn(default_test_config()).unwrap();
+        let mut server_events = server.subscribe();
+        let listener = server
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = listener.address().clone();
+        let mut requests = server.register_protocol(ECHO_PROTOCOL).await;
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let mut client_events = client.subscribe();
+        let request_task = {
+            let client = client.clone();
+            let server_peer_id = server.peer_id();
+            let server_addr = server_addr.clone();
+            tokio::spawn(async move {
+                client
+                    .request_protocol(server_peer_id, server_addr, ECHO_PROTOCOL, vec![])
+                    .await
+            })
+        };
+
+        // Drop the request without responding, the server closes the substream.
+        drop(requests.recv().await.expect("Should receive a request"));
+        let _ = request_task.await.unwrap();
+
+        match client_events.recv().await.unwrap() {
+            NetworkEvent::ConnectionEstablished {
+                peer_id,
+                address,
+                outbound,
+            } => {
+                assert_eq!(peer_id, server.peer_id());
+                // The swarm appends the peer ID to the addresses it dials.
+                let dialed_addr = server_addr
+                    .clone()
+                    .with(Protocol::P2p(server.peer_id().into()));
+                assert_eq!(address, dialed_addr);
+                assert!(outbound, "The client should have dialed the server");
+            }
+            event => panic!("Unexpected client event: {event:?}"),
+        }
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            events.push(server_events.recv().await.unwrap());
+        }
+        assert!(
+            matches!(&events[0], NetworkEvent::NewListenAddr { address } if *address == server_addr),
+            "Unexpected server event: {:?}",
+            events[0]
+        );
+        assert!(
+            matches!(
+                &events[1],
+                NetworkEvent::ConnectionEstablished { peer_id, outbound: false, .. }
+                    if *peer_id == client.peer_id()
+            ),
+            "Unexpected server event: {:?}",
+            events[1]
+        );
+        match &events[2] {
+            NetworkEvent::InboundFailure {
+                peer_id,
+                protocol,
+                error,
+            } => {
+                assert_eq!(*peer_id, client.peer_id());
+                assert_eq!(protocol.as_deref(), Some("/zinnia/echo/1.0.0"));
+                assert_eq!(*error, InboundFailure::ResponseOmission);
+            }
+            event => panic!("Unexpected server event: {event:?}"),
+        }
+
+        client.shutdown().await.unwrap();
+        loop {
+            match server_events.recv().await.unwrap() {
+                NetworkEvent::ConnectionClosed { peer_id, .. } => {
+                    assert_eq!(peer_id, client.peer_id());
+                    break;
+                }
+                event => println!("Server event: {event:?}"),
+            }
+        }
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn publishes_dial_failures() {
+        let unreachable_addr: Multiaddr = "/ip4/127.0.0.1/tcp/10".parse().unwrap();
+        let peer_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let mut events = peer.subscribe();
+        peer.dial(peer_id, unreachable_addr)
+            .await
+            .expect_err("Dial should have failed with an error");
+
+        match events.recv().await.unwrap() {
+            NetworkEvent::DialFailed {
+                peer_id: failed_peer_id,
+                addresses,
+                ..
+            } => {
+                assert_eq!(failed_peer_id, Some(peer_id));
+                assert_eq!(addresses[0].kind, std::io::ErrorKind::ConnectionRefused);
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
+        peer.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shares_failed_dial_in_progress() {
+        let unreachable_addr: Multiaddr = "/ip4/127.0.0.1/tcp/10".parse().unwrap();
+        let peer_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+
+        let first = PeerNode::spawn(default_test_config()).unwrap();
+        let second = first.clone();
+        let (first_result, second_result) = tokio::join!(
+            first.dial(peer_id, unreachable_addr.clone()),
+            second.dial(peer_id, unreachable_addr)
+        );
+        let first_err = first_result.expect_err("The first dial should fail");
+        let second_err = second_result.expect_err("The second dial should fail");
+        assert_eq!(first_err, second_err);
+        assert_eq!(first_err.code(), "ERR_CONNECTION_REFUSED");
+
+        drop(second);
+        first.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_responses_exceeding_size_limit() {
+        let (server, server_addr, echo_task) = spawn_echo_server().await;
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+
+        // The configured limit is 1024 bytes.
+        let err = client
+            .request_protocol(
+                server.peer_id(),
+                server_addr.clone(),
+                ECHO_PROTOCOL,
+                vec![1; 1025],
+            )
+            .await
+            .expect_err("The response should exceed the configured limit");
+        assert_eq!(
+            err,
+            PeerNodeError::ResponseTooLarge {
+                peer_id: server.peer_id(),
+                protocol: "/zinnia/echo/1.0.0".into(),
+            }
+        );
+
+        let response = client
+            .request_protocol(
+                server.peer_id(),
+                server_addr.clone(),
+                ECHO_PROTOCOL,
+                vec![1; 1024],
+            )
+            .await
+            .expect("The response should fit the configured limit");
+        assert_eq!(response.len(), 1024);
+
+        let options = RequestOptions {
+            max_response_size: Some(2048),
+            ..Default::default()
+        };
+        let response = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr.clone(),
+                ECHO_PROTOCOL,
+                vec![1; 2048],
+                options,
+            )
+            .await
+            .expect("The response should fit the limit of the request");
+        assert_eq!(response.payload.len(), 2048);
+
+        let options = RequestOptions {
+            max_response_size: Some(10),
+            ..Default::default()
+        };
+        let err = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr,
+                ECHO_PROTOCOL,
+                vec![1; 11],
+                options,
+            )
+            .await
+            .expect_err("The response should exceed the limit of the request");
+        assert_eq!(err.code(), "ERR_RESPONSE_TOO_LARGE");
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        echo_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn applies_request_timeout_and_deadline() {
+        let server = PeerNode::spawn(PeerNodeConfig {
+            request_response: RequestResponseConfig {
+                request_timeout: Duration::from_secs(5),
+                ..TEST_REQUEST_RESPONSE_CONFIG
+            },
+            ..default_test_config()
+        })
+        .unwrap();
+        let listener = server
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = listener.address().clone();
+        let mut requests = server.register_protocol(SLOW_PROTOCOL).await;
+        // Respond after the delay requested in the payload (in units of 100ms).
+        let slow_task = tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                tokio::spawn(async move {
+                    let delay = Duration::from_millis(100) * u32::from(request.payload[0]);
+                    tokio::time::sleep(delay).await;
+                    let _ = request.responder.respond(request.payload);
+                });
+            }
+        });
+
+        // The configured request timeout is 1 second.
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+
+        let options = RequestOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let err = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr.clone(),
+                SLOW_PROTOCOL,
+                vec![5],
+                options,
+            )
+            .await
+            .expect_err("The request should time out");
+        assert_eq!(err.code(), "ERR_TIMEOUT");
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "The request should fail after the timeout of the request"
+        );
+
+        let options = RequestOptions {
+            deadline: Some(std::time::Instant::now() + Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let err = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr.clone(),
+                SLOW_PROTOCOL,
+                vec![5],
+                options,
+            )
+            .await
+            .expect_err("The request should miss the deadline");
+        assert_eq!(err.code(), "ERR_TIMEOUT");
+
+        let options = RequestOptions {
+            timeout: Some(Duration::from_secs(3)),
+            ..Default::default()
+        };
+        let response = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr,
+                SLOW_PROTOCOL,
+                vec![15],
+                options,
+            )
+            .await
+            .expect("The request should finish within its own timeout");
+        assert_eq!(response.payload, vec![15]);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        slow_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_requests_past_their_deadline() {
+        // Accepts TCP connections in the backlog but never completes the handshake, the
+        // dial fails only once the transport times out after 5 seconds.
+        let stalled = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalled_addr: Multiaddr = format!(
+            "/ip4/127.0.0.1/tcp/{}",
+            stalled.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+
+        let client = PeerNode::spawn(PeerNodeConfig {
+            shutdown_timeout: Duration::from_secs(5),
+            ..default_test_config()
+        })
+        .unwrap();
+
+        let options = RequestOptions {
+            deadline: Some(std::time::Instant::now() + Duration::from_millis(200)),
+            retry: Some(Default::default()),
+            ..Default::default()
+        };
+        let err = client
+            .request_protocol_with_options(
+                PeerId::random(),
+                stalled_addr,
+                ECHO_PROTOCOL,
+                vec![1],
+                options,
+            )
+            .await
+            .expect_err("The request should miss the deadline");
+        assert_eq!(err.code(), "ERR_TIMEOUT");
+
+        // The shutdown waits for the pending requests, it would take the whole shutdown
+        // timeout if the expired request was still pending.
+        let started = std::time::Instant::now();
+        client.shutdown().await.unwrap();
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "The expired request should have been dropped from the pending requests"
+        );
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_older_protocol_versions() {
+        const FOO_V1: &[u8] = b"/zinnia/foo/1.0.0";
+        const FOO_V2: &[u8] = b"/zinnia/foo/2.0.0";
+
+        let (server, server_addr, requests) = spawn_server(FOO_V1).await;
+        let echo_task = spawn_echo(requests);
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let response = client
+            .request_protocols(
+                server.peer_id(),
+                server_addr.clone(),
+                &[FOO_V2, FOO_V1],
+                b"hi".to_vec(),
+                Default::default(),
+            )
+            .await
+            .expect("The request should fall back to the older version");
+        assert_eq!(response.protocol, FOO_V1);
+        assert_eq!(response.payload, b"hi".to_vec());
+
+        let err = client
+            .request_protocols(
+                server.peer_id(),
+                server_addr,
+                &[FOO_V2, b"/zinnia/foo/3.0.0"],
+                vec![],
+                Default::default(),
+            )
+            .await
+            .expect_err("The server supports none of the protocols");
+        assert_eq!(
+            err,
+            PeerNodeError::UnsupportedProtocols {
+                peer_id: server.peer_id(),
+                protocols: vec!["/zinnia/foo/2.0.0".into(), "/zinnia/foo/3.0.0".into()],
+            }
+        );
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        echo_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn identifies_connected_peers() {
+        let server = PeerNode::spawn(PeerNodeConfig {
+            agent_version: "zinnia-test/1.2.3".into(),
+            ..default_test_config()
+        })
+        .unwrap();
+        let listener = server
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = listener.address().clone();
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let mut events = client.subscribe();
+        assert_eq!(client.peer_info(server.peer_id()).await.unwrap(), None);
+        client
+            .dial(server.peer_id(), server_addr.clone())
+            .await
+            .unwrap();
+
+        let identified = loop {
+            match events.recv().await.unwrap() {
+                NetworkEvent::PeerIdentified { peer_id, info } if peer_id == server.peer_id() => {
+                    break info
+                }
+                _ => {}
+            }
+        };
+        assert_eq!(identified.agent_version, "zinnia-test/1.2.3");
+        assert_eq!(identified.protocol_version, IDENTIFY_PROTOCOL_VERSION);
+        assert!(identified.protocols.contains(&"/ipfs/id/1.0.0".to_string()));
+        assert!(identified
+            .protocols
+            .contains(&"/ipfs/ping/1.0.0".to_string()));
+        assert_eq!(identified.listen_addrs, vec![server_addr]);
+        assert_eq!(
+            identified.observed_addr.iter().next(),
+            Some(Protocol::Ip4([127, 0, 0, 1].into()))
+        );
+
+        let info = client.peer_info(server.peer_id()).await.unwrap();
+        assert_eq!(info, Some(identified));
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn probes_supported_protocols() {
+        const FOO_V1: &[u8] = b"/zinnia/foo/1.0.0";
+        const FOO_V2: &[u8] = b"/zinnia/foo/2.0.0";
+
+        let (server, server_addr, mut requests) = spawn_server(FOO_V1).await;
+        server.set_codec(FOO_V1, LengthPrefixed).await;
+        let mut server_events = server.subscribe();
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        // Close-delimited requests cannot be told apart from the probe, see `supports`.
+        let err = client
+            .supports(server.peer_id(), server_addr.clone(), &[FOO_V2, FOO_V1])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, PeerNodeError::UnprobeableProtocol { protocol } if protocol == "/zinnia/foo/2.0.0"),
+            "Unexpected error: {err:?}"
+        );
+        assert_eq!(err.code(), "ERR_UNPROBEABLE_PROTOCOL");
+
+        for protocol in [FOO_V1, FOO_V2] {
+            client.set_codec(protocol, LengthPrefixed).await;
+        }
+        client
+            .set_codec(libp2p::ping::PROTOCOL_NAME, FixedLength { size: 32 })
+            .await;
+        let supported = client
+            .supports(
+                server.peer_id(),
+                server_addr.clone(),
+                &[FOO_V2, FOO_V1, libp2p::ping::PROTOCOL_NAME],
+            )
+            .await
+            .expect("Should be able to probe the protocols");
+        assert_eq!(
+            supported,
+            vec![FOO_V1.to_vec(), libp2p::ping::PROTOCOL_NAME.to_vec()]
+        );
+        // Probing must not look like a request to the remote peer.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), requests.recv())
+                .await
+                .is_err(),
+            "The server should not receive any request"
+        );
+        // The remote peer sees the probe of a protocol it doesn't support as such, and no
+        // request at all for the protocols it supports.
+        while let Ok(event) = server_events.try_recv() {
+            if let NetworkEvent::InboundFailure { error, .. } = &event {
+                assert!(
+                    matches!(error, InboundFailure::UnsupportedProtocols { .. }),
+                    "Unexpected server event: {event:?}"
+                );
+            }
+        }
+
+        let supported = client
+            .supports(server.peer_id(), server_addr, &[FOO_V2])
+            .await
+            .unwrap();
+        assert!(supported.is_empty());
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    enum Muxer {
+        Yamux,
+        Mplex,
+    }
+
+    /// Like [`create_transport`], negotiating only the given muxer.
+    fn create_transport_with_muxer(
+        id_keys: &identity::Keypair,
+        muxer: Muxer,
+    ) -> transport::Boxed<(PeerId, StreamMuxerBox)> {
+        let transport = libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::new())
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::NoiseAuthenticated::xx(id_keys).unwrap());
+        match muxer {
+            Muxer::Yamux => transport.multiplex(yamux::YamuxConfig::default()).boxed(),
+            Muxer::Mplex => transport
+                .multiplex(libp2p::mplex::MplexConfig::default())
+                .boxed(),
+        }
+    }
+
+    /// A swarm of the bare request-response behaviour, negotiating only the given muxer,
+    /// which knows the address of the server.
+    fn client_swarm_with_muxer(
+        muxer: Muxer,
+        server_peer_id: PeerId,
+        server_addr: Multiaddr,
+    ) -> Swarm<RequestResponse> {
+        let client_id_keys = identity::Keypair::generate_ed25519();
+        let client_peer_id = client_id_keys.public().to_peer_id();
+        let mut client_swarm = Swarm::with_tokio_executor(
+            create_transport_with_muxer(&client_id_keys, muxer),
+            RequestResponse::new(TEST_REQUEST_RESPONSE_CONFIG),
+            client_peer_id,
+        );
+        client_swarm
+            .behaviour_mut()
+            .add_address(&server_peer_id, server_addr);
+        client_swarm
+    }
+
+    #[tokio::test]
+    async fn keeps_connections_after_responding_to_reset_substreams() {
+        const RESET_PROTOCOL: &[u8] = b"/zinnia/reset/1.0.0";
+
+        for muxer in [Muxer::Yamux, Muxer::Mplex] {
+            let (server, server_addr, mut requests) = spawn_server(RESET_PROTOCOL).await;
+            server.set_codec(RESET_PROTOCOL, LengthPrefixed).await;
+            let mut server_events = server.subscribe();
+
+            let server_peer_id = server.peer_id();
+            let mut client_swarm = client_swarm_with_muxer(muxer, server_peer_id, server_addr);
+            client_swarm
+                .behaviour_mut()
+                .open_stream(&server_peer_id, &[RESET_PROTOCOL.into()]);
+            let client_task = tokio::spawn(async move {
+                loop {
+                    if let SwarmEvent::Behaviour(RequestResponseEvent::StreamOpened {
+                        mut stream,
+                        ..
+                    }) = client_swarm.select_next_some().await
+                    {
+                        // Send the request and reset the substream instead of waiting for
+                        // the response.
+                        LengthPrefixed
+                            .write_payload(&mut stream, b"hi")
+                            .await
+                            .unwrap();
+                        drop(stream);
+                        // Yamux resets the dropped substream only when the connection
+                        // makes progress, e.g. opens another substream.
+                        client_swarm
+                            .behaviour_mut()
+                            .open_stream(&server_peer_id, &[RESET_PROTOCOL.into()]);
+                    }
+                }
+            });
+
+            // The reset is reported as the end of the substream, the server learns about it
+            // only when writing the response fails.
+            let request = requests.recv().await.expect("Should receive a request");
+            assert_eq!(request.payload, b"hi");
+            let _next_request = requests.recv().await.expect("Should receive a request");
+            request.responder.respond(b"hello".to_vec()).unwrap();
+
+            // Failing to write the response affects only the substream.
+            for _ in 0..2 {
+                let request = tokio::time::timeout(Duration::from_secs(5), requests.recv())
+                    .await
+                    .unwrap_or_else(|_| panic!("Should receive more requests on {muxer:?}"))
+                    .expect("Should receive a request");
+                assert_eq!(request.payload, b"hi");
+            }
+            while let Ok(event) = server_events.try_recv() {
+                assert!(
+                    !matches!(event, NetworkEvent::ConnectionClosed { .. }),
+                    "Unexpected server event on {muxer:?}: {event:?}"
+                );
+            }
+
+            client_task.abort();
+            server.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn responds_after_the_remote_closed_its_writer() {
+        for muxer in [Muxer::Yamux, Muxer::Mplex] {
+            // Neither the end of the request substream nor a stray byte after the request
+            // are a reason to drop the request.
+            for trailer in [&b""[..], b"\0"] {
+                let (server, server_addr, requests) = spawn_server(ECHO_PROTOCOL).await;
+                server.set_codec(ECHO_PROTOCOL, LengthPrefixed).await;
+                let echo_task = spawn_echo(requests);
+
+                let server_peer_id = server.peer_id();
+                let mut client_swarm = client_swarm_with_muxer(muxer, server_peer_id, server_addr);
+                client_swarm
+                    .behaviour_mut()
+                    .open_stream(&server_peer_id, &[ECHO_PROTOCOL.into()]);
+                let mut stream = loop {
+                    if let SwarmEvent::Behaviour(RequestResponseEvent::StreamOpened {
+                        stream,
+                        ..
+                    }) = client_swarm.select_next_some().await
+                    {
+                        break stream;
+                    }
+                };
+
+                LengthPrefixed
+                    .write_payload(&mut stream, b"hi")
+                    .await
+                    .unwrap();
+                stream.write_all(trailer).await.unwrap();
+                stream.close().await.unwrap();
+                let response = tokio::time::timeout(
+                    Duration::from_secs(5),
+                    LengthPrefixed.read_payload(&mut stream, 1024),
+                )
+                .await
+                .unwrap_or_else(|_| panic!("Should receive the response on {muxer:?}"))
+                .unwrap_or_else(|err| panic!("Should receive the response on {muxer:?}: {err}"));
+                assert_eq!(response, b"hi");
+
+                server.shutdown().await.unwrap();
+                echo_task.await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dials_peers_found_in_dht() {
+        let (bootstrap, server, client) = spawn_dht_nodes().await;
+        client
+            .dial_peer(server.peer_id())
+            .await
+            .expect("Should find the server via the bootstrap node");
+
+        let unknown_peer_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+        let err = client
+            .dial_peer(unknown_peer_id)
+            .await
+            .expect_err("The peer is not in the DHT");
+        assert_eq!(err.code(), "ERR_DIAL_FAILED");
+
+        // Without the DHT, only the peers dialed before can be dialed by their ID.
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let err = peer.dial_peer(server.peer_id()).await.unwrap_err();
+        assert_eq!(err.peer_id(), Some(server.peer_id()));
+
+        for node in [peer, client, server, bootstrap] {
+            node.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_response_metadata() {
+        let (server, server_addr, echo_task) = spawn_echo_server().await;
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let first = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr.clone(),
+                ECHO_PROTOCOL,
+                b"hello".to_vec(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let metadata = first.metadata;
+        // The swarm appends the peer ID to the addresses it dials.
+        let dialed_addr = server_addr
+            .clone()
+            .with(Protocol::P2p(server.peer_id().into()));
+        assert_eq!(metadata.remote_address, dialed_addr);
+        assert_eq!(metadata.payload_bytes_sent, 5);
+        assert_eq!(metadata.payload_bytes_received, 5);
+        let first_byte = metadata.first_byte.expect("The response is not empty");
+        assert!(
+            metadata.queued + metadata.substream_open + metadata.negotiation + first_byte
+                <= metadata.total
+        );
+
+        // The second request reuses the connection.
+        let second = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr,
+                ECHO_PROTOCOL,
+                vec![],
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(second.metadata.connection_id, metadata.connection_id);
+        assert_eq!(second.metadata.payload_bytes_received, 0);
+        assert_eq!(second.metadata.first_byte, None);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        echo_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn frames_payloads_with_protocol_codecs() {
+        const LENGTH_PREFIXED_PROTOCOL: &[u8] = b"/zinnia/length-prefixed/1.0.0";
+        const FIXED_LENGTH_PROTOCOL: &[u8] = b"/zinnia/fixed-length/1.0.0";
+
+        let server = PeerNode::spawn(default_test_config()).unwrap();
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        for peer in [&server, &client] {
+            peer.set_codec(LENGTH_PREFIXED_PROTOCOL, LengthPrefixed)
+                .await;
+            peer.set_codec(FIXED_LENGTH_PROTOCOL, FixedLength { size: 4 })
+                .await;
+        }
+
+        let listener = server
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = listener.address().clone();
+        let mut length_prefixed_requests = server.register_protocol(LENGTH_PREFIXED_PROTOCOL).await;
+        let mut fixed_length_requests = server.register_protocol(FIXED_LENGTH_PROTOCOL).await;
+        let echo_task = tokio::spawn(async move {
+            loop {
+                let request = tokio::select! {
+                    Some(request) = length_prefixed_requests.recv() => request,
+                    Some(request) = fixed_length_requests.recv() => request,
+                    else => break,
+                };
+                let _ = request.responder.respond(request.payload);
+            }
+        });
+
+        // Longer than a single byte of the length prefix.
+        let payload = vec![7; 300];
+        let response = client
+            .request_protocol(
+                server.peer_id(),
+                server_addr.clone(),
+                LENGTH_PREFIXED_PROTOCOL,
+                payload.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response, payload);
+
+        let response = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr.clone(),
+                FIXED_LENGTH_PROTOCOL,
+                b"ping".to_vec(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.payload, b"ping".to_vec());
+
+        // A payload of the wrong size fails the request only, the connection stays open.
+        let err = client
+            .request_protocol(
+                server.peer_id(),
+                server_addr.clone(),
+                FIXED_LENGTH_PROTOCOL,
+                b"pong!".to_vec(),
+            )
+            .await
+            .expect_err("The payload should be rejected");
+        assert_eq!(err.code(), "ERR_INVALID_PAYLOAD");
+        assert_eq!(
+            err.to_string(),
+            "Cannot send the /zinnia/fixed-length/1.0.0 request: The payload must have 4 bytes, got 5 bytes"
+        );
+        let next_response = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr.clone(),
+                FIXED_LENGTH_PROTOCOL,
+                b"pong".to_vec(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(next_response.payload, b"pong".to_vec());
+        assert_eq!(
+            next_response.metadata.connection_id,
+            response.metadata.connection_id
+        );
+
+        // The length prefix tells the response is too large before reading it.
+        let options = RequestOptions {
+            max_response_size: Some(100),
+            ..Default::default()
+        };
+        let err = client
+            .request_protocol_with_options(
+                server.peer_id(),
+                server_addr,
+                LENGTH_PREFIXED_PROTOCOL,
+                payload,
+                options,
+            )
+            .await
+            .expect_err("The response should exceed the limit of the request");
+        assert_eq!(err.code(), "ERR_RESPONSE_TOO_LARGE");
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        echo_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_failed_requests() {
+        // Find a free port, the server starts listening on it only after the first attempt.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let server_keypair = identity::Keypair::generate_ed25519();
+        let server_peer_id = server_keypair.public().to_peer_id();
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        let options = RequestOptions {
+            retry: Some(RetryPolicy {
+                max_attempts: 10,
+                initial_backoff: Duration::from_millis(100),
+                max_backoff: Duration::from_millis(200),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let request = tokio::spawn({
+            let client = client.clone();
+            let server_addr = server_addr.clone();
+            async move {
+                client
+                    .request_protocol_with_options(
+                        server_peer_id,
+                        server_addr,
+                        ECHO_PROTOCOL,
+                        b"hi".to_vec(),
+                        options,
+                    )
+                    .await
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let server = PeerNode::spawn(PeerNodeConfig {
+            identity: NodeIdentity::Keypair(server_keypair),
+            ..default_test_config()
+        })
+        .unwrap();
+        let echo_task = spawn_echo(server.register_protocol(ECHO_PROTOCOL).await);
+        let _listener = server.listen_on(server_addr.clone()).await.unwrap();
+
+        let response = request
+            .await
+            .unwrap()
+            .expect("The request should succeed once the server is up");
+        assert_eq!(response.payload, b"hi".to_vec());
+        assert!(
+            response.attempts > 1,
+            "The request should have been retried"
+        );
+
+        // Errors which are not retryable fail right away.
+        let options = RequestOptions {
+            retry: Some(Default::default()),
+            ..Default::default()
+        };
+        let err = client
+            .request_protocol_with_options(
+                server_peer_id,
+                server_addr,
+                b"/zinnia/unknown/1.0.0",
+                vec![],
+                options,
+            )
+            .await
+            .expect_err("The request should fail");
+        assert_eq!(err.code(), "ERR_UNSUPPORTED_PROTOCOLS");
+
+        // Timeouts are retried only when enabled, the remote peer may have processed the
+        // request already.
+        let (slow_server, slow_server_addr, mut slow_requests) = spawn_server(SLOW_PROTOCOL).await;
+        let request_slow = |retry| {
+            client.request_protocol_with_options(
+                slow_server.peer_id(),
+                slow_server_addr.clone(),
+                SLOW_PROTOCOL,
+                vec![],
+                RequestOptions {
+                    timeout: Some(Duration::from_millis(200)),
+                    retry: Some(retry),
+                    ..Default::default()
+                },
+            )
+        };
+        let err = request_slow(Default::default())
+            .await
+            .expect_err("The request should time out");
+        assert!(
+            matches!(err, PeerNodeError::Timeout { .. }),
+            "Unexpected error: {err:?}"
+        );
+        let err = request_slow(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(10),
+            retry_on_timeout: true,
+            ..Default::default()
+        })
+        .await
+        .expect_err("The request should time out");
+        assert!(
+            matches!(err, PeerNodeError::RetryFailed { attempts: 2, .. }),
+            "Unexpected error: {err:?}"
+        );
+        assert_eq!(err.code(), "ERR_TIMEOUT");
+        slow_server.shutdown().await.unwrap();
+        let mut slow_request_count = 0;
+        while slow_requests.recv().await.is_some() {
+            slow_request_count += 1;
+        }
+        assert_eq!(slow_request_count, 3);
+
+        // The last error tells why the request failed after all attempts.
+        let options = RequestOptions {
+            retry: Some(RetryPolicy {
+                max_attempts: 2,
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let unreachable_peer_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+        let err = client
+            .request_protocol_with_options(
+                unreachable_peer_id,
+                "/ip4/127.0.0.1/tcp/10".parse().unwrap(),
+                ECHO_PROTOCOL,
+                vec![],
+                options,
+            )
+            .await
+            .expect_err("The request should fail");
+        match &err {
+            PeerNodeError::RetryFailed {
+                attempts,
+                last_error,
+            } => {
+                assert_eq!(*attempts, 2);
+                // The failures of the individual addresses are kept.
+                match &**last_error {
+                    PeerNodeError::Dial(failure) => {
+                        assert_eq!(failure.addresses.len(), 1);
+                        assert_eq!(failure.protocol.as_deref(), Some("/zinnia/echo/1.0.0"));
+                    }
+                    err => panic!("Unexpected last error: {err:?}"),
+                }
+            }
+            err => panic!("Unexpected error: {err:?}"),
+        }
+        assert_eq!(err.code(), "ERR_CONNECTION_REFUSED");
+        let prefix = format!("Cannot dial peer {unreachable_peer_id} for /zinnia/echo/1.0.0: ");
+        assert!(err.to_string().starts_with(&prefix), "{err}");
+        assert_eq!(err.peer_id(), Some(unreachable_peer_id));
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        echo_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_listen_error() {
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let err = peer
+            .listen_on("/dns4/localhost/tcp/0".parse().unwrap())
+            .await
+            .expect_err("Listening on an unsupported address should fail");
+        assert_eq!(err.code(), "ERR_LISTEN_FAILED");
+    }
+
+    #[tokio::test]
+    async fn reports_unsupported_protocol() {
+        const UNKNOWN_PROTOCOL: &[u8] = b"/zinnia/unknown/1.0.0";
+
+        let (server_peer_id, server_addr, cancellation_token, server_task) =
+            spawn_ping_server().await;
+
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        let err = peer
+            .request_protocol(server_peer_id, server_addr, UNKNOWN_PROTOCOL, vec![])
+            .await
+            .expect_err("Request for an unknown protocol should fail");
+        assert_eq!(
+            err,
+            PeerNodeError::UnsupportedProtocols {
+                peer_id: server_peer_id,
+                protocols: vec!["/zinnia/unknown/1.0.0".into()],
+            }
+        );
+        assert_eq!(err.code(), "ERR_UNSUPPORTED_PROTOCOLS");
+
+        cancellation_token.cancel();
+        let _ = server_task.await;
+    }
+
+    #[tokio::test]
+    async fn finds_content_providers_in_dht() {
+        const KEY: &[u8] = b"bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+        let (bootstrap, server, client) = spawn_dht_nodes().await;
+        server
+            .start_providing(KEY)
+            .await
+            .expect("Should be able to publish the provider record");
+
+        let mut providers = client.find_providers(KEY, 1).await.unwrap();
+        let provider = providers.recv().await.expect("Should find the server");
+        assert_eq!(provider.peer_id, server.peer_id());
+        let server_addrs = server.listen_addrs().await.unwrap();
+        assert!(provider.addresses.contains(&server_addrs[0]));
+        assert_eq!(
+            providers.recv().await,
+            None,
+            "The limit should be respected"
+        );
+
+        let mut providers = client.find_providers(b"unknown", 10).await.unwrap();
+        assert_eq!(providers.recv().await, None);
+
+        server.stop_providing(KEY).await.unwrap();
+
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        assert_eq!(
+            peer.start_providing(KEY).await,
+            Err(PeerNodeError::DhtDisabled)
+        );
+        assert_eq!(
+            peer.find_providers(KEY, 1).await.unwrap_err(),
+            PeerNodeError::DhtDisabled
+        );
+
+        for node in [peer, client, server, bootstrap] {
+            node.shutdown().await.unwrap();
+        }
+    }
+
+    fn dht_test_config(bootstrap_peers: Vec<(PeerId, Multiaddr)>) -> PeerNodeConfig {
+        PeerNodeConfig {
+            listen_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            dht: Some(DhtConfig {
+                protocol_name: b"/zinnia/kad/test".to_vec(),
+                bootstrap_peers,
+                query_timeout: Duration::from_secs(5),
+                ..Default::default()
+            }),
+            ..default_test_config()
+        }
+    }
+
+    /// Spawns a bootstrap node and a server and a client joining the DHT through it.
+    ///
+    /// Returns once the bootstrap node has learned the addresses of the server.
+    async fn spawn_dht_nodes() -> (PeerNode, PeerNode, PeerNode) {
+        let bootstrap = PeerNode::spawn(dht_test_config(vec![])).unwrap();
+        let mut bootstrap_events = bootstrap.subscribe();
+        let bootstrap_addr = loop {
+            if let NetworkEvent::NewListenAddr { address } = bootstrap_events.recv().await.unwrap()
+            {
+                break address;
+            }
+        };
+        let bootstrap_peers = vec![(bootstrap.peer_id(), bootstrap_addr)];
+
+        let server = PeerNode::spawn(dht_test_config(bootstrap_peers.clone())).unwrap();
+        loop {
+            match bootstrap_events.recv().await.unwrap() {
+                NetworkEvent::PeerIdentified { peer_id, info }
+                    if peer_id == server.peer_id() && !info.listen_addrs.is_empty() =>
+                {
+                    break
+                }
+                _ => {}
+            }
+        }
 
-        // FIXME: Use an ephemeral port number here.
-        // Listen on port 0, read back the port assigned by the OS
-        let server_addr: Multiaddr = "/ip4/127.0.0.1/tcp/10458".parse().unwrap();
-        server_transport.listen_on(server_addr.clone()).unwrap();
+        let client = PeerNode::spawn(dht_test_config(bootstrap_peers)).unwrap();
+        (bootstrap, server, client)
+    }
+
+    /// Starts listening on a port assigned by the OS and returns the bound address.
+    async fn listen_on_ephemeral_port<B: NetworkBehaviour>(swarm: &mut Swarm<B>) -> Multiaddr
+    where
+        B::OutEvent: std::fmt::Debug,
+    {
+        swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::NewListenAddr { address, .. } => return address,
+                event => println!("Unexpected swarm event: {event:?}"),
+            }
+        }
+    }
+
+    /// Answers pings and keeps the connections alive, the server would close them as idle
+    /// before the clients get to send their requests otherwise.
+    #[derive(NetworkBehaviour)]
+    struct PingServerBehaviour {
+        keep_alive: libp2p::swarm::keep_alive::Behaviour,
+        ping: libp2p::ping::Behaviour,
+    }
+
+    /// Starts a swarm running the libp2p ping protocol, listening on an ephemeral port.
+    async fn spawn_ping_server() -> (PeerId, Multiaddr, CancellationToken, JoinHandle<()>) {
+        let cancellation_token = CancellationToken::new();
+
+        let server_id_keys = identity::Keypair::generate_ed25519();
+        let server_peer_id = server_id_keys.public().to_peer_id();
 
         let mut server_swarm = Swarm::with_tokio_executor(
-            server_transport,
-            libp2p::ping::Behaviour::new(
-                libp2p::ping::Config::new()
-                    .with_max_failures(std::num::NonZeroU32::new(10).unwrap()),
-            ),
+            create_transport(&server_id_keys).unwrap(),
+            PingServerBehaviour {
+                keep_alive: Default::default(),
+                ping: libp2p::ping::Behaviour::new(
+                    libp2p::ping::Config::new()
+                        .with_max_failures(std::num::NonZeroU32::new(10).unwrap()),
+                ),
+            },
             server_peer_id,
         );
+        let server_addr = listen_on_ephemeral_port(&mut server_swarm).await;
         let server_task = {
             let token = cancellation_token.clone();
             tokio::spawn(async move {
@@ -458,26 +4220,234 @@ mod tests {
             })
         };
 
-        let mut peer = PeerNode::spawn(DEFAULT_TEST_CONFIG.clone()).unwrap();
-        peer.dial(server_peer_id, server_addr.clone())
+        (server_peer_id, server_addr, cancellation_token, server_task)
+    }
+
+    #[tokio::test]
+    async fn dials_peers_through_relay() {
+        let (relay_peer_id, relay_addr, cancellation_token, relay_task) =
+            spawn_relay_server().await;
+        let circuit_addr = relay::circuit_addr(relay_peer_id, relay_addr);
+
+        // The destination keeps a reservation on the relay to be reachable through it.
+        let destination_id_keys = identity::Keypair::generate_ed25519();
+        let destination_peer_id = destination_id_keys.public().to_peer_id();
+        let (relay_transport, relay_client) =
+            relay_client::Client::new_transport_and_behaviour(destination_peer_id);
+        let mut destination_swarm = Swarm::with_tokio_executor(
+            create_transport_with_relay(&destination_id_keys, Some(relay_transport)).unwrap(),
+            relay_client,
+            destination_peer_id,
+        );
+        destination_swarm.listen_on(circuit_addr.clone()).unwrap();
+        loop {
+            if let SwarmEvent::Behaviour(relay_client::Event::ReservationReqAccepted { .. }) =
+                destination_swarm.select_next_some().await
+            {
+                break;
+            }
+        }
+        let destination_task = {
+            let token = cancellation_token.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        event = destination_swarm.next() => println!("Destination swarm event: {event:?}"),
+                        _ = token.cancelled() => break,
+                    }
+                }
+            })
+        };
+
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        peer.dial(destination_peer_id, circuit_addr.clone())
             .await
-            .expect("Should be able to dial a remote peer.");
+            .expect("Should be able to dial the destination through the relay");
+
+        let unknown_peer_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+        let err = peer
+            .dial(unknown_peer_id, circuit_addr)
+            .await
+            .expect_err("The peer has no reservation on the relay");
+        assert!(
+            matches!(
+                &err,
+                PeerNodeError::RelayedDial(failure) if failure.hop == RelayHop::Destination
+            ),
+            "{err:?}"
+        );
 
-        // TODO: find out why the server does not respond to our ping request with Err(Unsupported)
-        // let request = crate::ping::new_request_payload();
-        // let response = peer
-        //     .request_protocol(
-        //         server_peer_id,
-        //         server_addr.clone(),
-        //         libp2p::ping::PROTOCOL_NAME,
-        //         request.clone(),
-        //     )
-        //     .await
-        //     .expect("Should be able to send PING request");
-        // assert_eq!(response, request, "PING response should match the request");
+        let unreachable_relay_addr: Multiaddr =
+            format!("/ip4/127.0.0.1/tcp/10/p2p/{unknown_peer_id}/p2p-circuit")
+                .parse()
+                .unwrap();
+        let err = peer
+            .dial(destination_peer_id, unreachable_relay_addr)
+            .await
+            .expect_err("The relay is not reachable");
+        assert!(
+            matches!(
+                &err,
+                PeerNodeError::RelayedDial(failure) if failure.hop == RelayHop::Relay
+            ),
+            "{err:?}"
+        );
+        assert_eq!(err.code(), "ERR_RELAY_UNREACHABLE");
 
+        peer.shutdown().await.unwrap();
         cancellation_token.cancel();
-        let _ = server_task.await;
+        let _ = destination_task.await;
+        let _ = relay_task.await;
+    }
+
+    #[tokio::test]
+    async fn keeps_reservations_on_relays() {
+        let (relay_peer_id, relay_addr, cancellation_token, relay_task) =
+            spawn_relay_server().await;
+
+        let server = PeerNode::spawn(PeerNodeConfig {
+            relays: vec![(relay_peer_id, relay_addr)],
+            ..default_test_config()
+        })
+        .unwrap();
+        let mut server_events = server.subscribe();
+        let circuit_addr = loop {
+            match server_events.recv().await.unwrap() {
+                NetworkEvent::NewListenAddr { address } => break address,
+                NetworkEvent::ReservationLost { message, .. } => panic!("{message}"),
+                _ => {}
+            }
+        };
+        assert_eq!(relay::relay_peer_id(&circuit_addr), Some(relay_peer_id));
+        assert_eq!(
+            server.listen_addrs().await.unwrap(),
+            vec![circuit_addr.clone()]
+        );
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        client
+            .dial(server.peer_id(), circuit_addr)
+            .await
+            .expect("Should be able to dial the server through the relay");
+
+        cancellation_token.cancel();
+        let _ = relay_task.await;
+        loop {
+            if let NetworkEvent::ReservationLost {
+                relay_peer_id: peer_id,
+                ..
+            } = server_events.recv().await.unwrap()
+            {
+                assert_eq!(peer_id, relay_peer_id);
+                break;
+            }
+        }
+        assert!(server.listen_addrs().await.unwrap().is_empty());
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn relays_connections_for_other_peers() {
+        // The relay advertises only the configured addresses, which must be known upfront.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let relay_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let relay = PeerNode::spawn(PeerNodeConfig {
+            listen_addrs: vec![relay_addr.clone()],
+            relay_server: Some(RelayServerConfig {
+                external_addrs: vec![relay_addr.clone()],
+                ..Default::default()
+            }),
+            ..default_test_config()
+        })
+        .unwrap();
+
+        let server = PeerNode::spawn(PeerNodeConfig {
+            relays: vec![(relay.peer_id(), relay_addr.clone())],
+            ..default_test_config()
+        })
+        .unwrap();
+        let mut server_events = server.subscribe();
+        let circuit_addr = loop {
+            if let NetworkEvent::NewListenAddr { address } = server_events.recv().await.unwrap() {
+                break address;
+            }
+        };
+
+        let client = PeerNode::spawn(default_test_config()).unwrap();
+        client
+            .dial(server.peer_id(), circuit_addr.clone())
+            .await
+            .expect("Should be able to dial the server through the relay");
+
+        let unknown_peer_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+        client
+            .dial(
+                unknown_peer_id,
+                relay::circuit_addr(relay.peer_id(), relay_addr),
+            )
+            .await
+            .expect_err("The peer has no reservation on the relay");
+
+        // The relay may learn about the denied circuit after the client did.
+        let stats = loop {
+            let stats = relay
+                .relay_stats()
+                .await
+                .unwrap()
+                .expect("The relay server should be enabled");
+            if stats.denied_circuits > 0 {
+                break stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(stats.reserved_peers, HashSet::from([server.peer_id()]));
+        assert_eq!(stats.accepted_circuits, 1);
+        assert_eq!(stats.denied_circuits, 1);
+        assert_eq!(stats.active_circuits.len(), 1);
+        assert_eq!(stats.active_circuits[0].src_peer_id, client.peer_id());
+        assert_eq!(stats.active_circuits[0].dst_peer_id, server.peer_id());
+
+        assert_eq!(client.relay_stats().await.unwrap(), None);
+
+        for node in [client, server, relay] {
+            node.shutdown().await.unwrap();
+        }
+    }
+
+    /// Starts a swarm running the circuit relay server, listening on an ephemeral port.
+    async fn spawn_relay_server() -> (PeerId, Multiaddr, CancellationToken, JoinHandle<()>) {
+        let cancellation_token = CancellationToken::new();
+
+        let relay_id_keys = identity::Keypair::generate_ed25519();
+        let relay_peer_id = relay_id_keys.public().to_peer_id();
+
+        let mut relay_swarm = Swarm::with_tokio_executor(
+            create_transport(&relay_id_keys).unwrap(),
+            libp2p::relay::v2::relay::Relay::new(relay_peer_id, Default::default()),
+            relay_peer_id,
+        );
+        let relay_addr = listen_on_ephemeral_port(&mut relay_swarm).await;
+        // The relay sends its external addresses to the peers making reservations.
+        relay_swarm.add_external_address(relay_addr.clone(), libp2p::swarm::AddressScore::Infinite);
+        let relay_task = {
+            let token = cancellation_token.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        event = relay_swarm.next() => println!("Relay swarm event: {event:?}"),
+                        _ = token.cancelled() => break,
+                    }
+                }
+            })
+        };
+
+        (relay_peer_id, relay_addr, cancellation_token, relay_task)
     }
 
     #[tokio::test]
@@ -499,37 +4469,27 @@ mod tests {
         println!("peer_addr: {peer_addr:?}");
         println!("peer id: {peer_id:?}");
 
-        let mut peer = PeerNode::spawn(DEFAULT_TEST_CONFIG.clone()).unwrap();
-        let result = peer.dial(peer_id, peer_addr).await;
-        let err = result
-            .expect_err("Dial should have failed with an error")
-            .downcast::<DialError>()
-            .expect("Dial should fail with DialError");
-        match *err {
-            DialError::Transport(transport_errs) => {
-                let (addr, err) = transport_errs.first().unwrap();
-                let io_err = match err {
-                    TransportError::Other(io_err) => io_err,
-                    _ => panic!("Unexpected TransportError: {err:?}"),
-                };
-                assert_eq!(io_err.kind(), std::io::ErrorKind::Other);
-                // TODO: figure out how to assert that we have a transport error
-                // with kind: ConnectionRefused
-                // This is what Debug prints for the value:
-                // println!("source: {:?}", io_err.source().unwrap());
-                // A(A(Transport(Os { code: 61, kind: ConnectionRefused, message: "Connection refused" })))
-                // assert_eq!(io_err.kind(), std::io::ErrorKind::ConnectionRefused);
-
-                assert_eq!(addr.to_string(), unreachable_addr);
-
-                if transport_errs.len() > 1 {
-                    panic!(
-                        "Expected exactly one transport error, found {:?}",
-                        transport_errs,
-                    )
+        let peer = PeerNode::spawn(default_test_config()).unwrap();
+        // The address ends with the peer ID already, it is dialed as it is.
+        let result = peer.dial(peer_id, peer_addr.clone()).await;
+        let err = result.expect_err("Dial should have failed with an error");
+        assert_eq!(err.code(), "ERR_CONNECTION_REFUSED");
+        assert_eq!(err.peer_id(), Some(peer_id));
+        let prefix = format!("Cannot dial peer {peer_id}: ");
+        assert!(err.to_string().starts_with(&prefix), "{err}");
+        match err {
+            PeerNodeError::Dial(failure) => {
+                assert_eq!(failure.protocol, None);
+                let addresses = &failure.addresses;
+                let failure = addresses.first().unwrap();
+                assert_eq!(failure.kind, std::io::ErrorKind::ConnectionRefused);
+                assert_eq!(failure.address, peer_addr);
+
+                if addresses.len() > 1 {
+                    panic!("Expected exactly one transport error, found {addresses:?}")
                 }
             }
-            _ => panic!("Unexpected DialError: {err:?}"),
+            _ => panic!("Unexpected PeerNodeError: {err:?}"),
         }
     }
 }
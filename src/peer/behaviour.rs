@@ -21,37 +21,119 @@
 // DEALINGS IN THE SOFTWARE.
 
 use libp2p::core::{connection::ConnectionId, ConnectedPoint, Multiaddr, PeerId};
+use libp2p::futures::channel::oneshot;
 use libp2p::swarm::{
     behaviour::{AddressChange, ConnectionClosed, ConnectionEstablished, DialFailure, FromSwarm},
     dial_opts::DialOpts,
-    IntoConnectionHandler, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
+    IntoConnectionHandler, NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction,
+    NotifyHandler, PollParameters,
 };
 use smallvec::SmallVec;
 use std::{
+    cell::Cell,
     collections::{HashMap, HashSet, VecDeque},
     fmt,
+    sync::{atomic::AtomicU64, Arc},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-pub use super::handler::{ProtocolInfo, ProtocolName, RequestPayload, ResponsePayload};
+pub use super::handler::{
+    CloseDelimited, Codec, FixedLength, LengthPrefixed, ProtocolInfo, RequestPayload,
+    ResponsePayload,
+};
+
+use super::handler::{codec_of, Codecs, InboundProtocols, RequestKind, RequestTimings};
+use super::negotiation::RejectedProtocols;
+use super::retry::RetryPolicy;
 
-use super::handler::{RequestProtocol, RequestResponseHandler, RequestResponseHandlerEvent};
+use super::handler::{
+    RequestProtocol, RequestResponseHandler, RequestResponseHandlerEvent,
+    RequestResponseHandlerProto,
+};
 
 /// An inbound request or response.
 #[derive(Debug)]
 pub enum RequestResponseMessage {
+    /// A request message.
+    Request {
+        /// The protocol negotiated for the request.
+        protocol: ProtocolInfo,
+        /// The request message.
+        request: RequestPayload,
+        /// The channel waiting for the response.
+        ///
+        /// If this channel is dropped instead of being used to send a response
+        /// via [`ResponseChannel::send`], an [`InboundFailure::ResponseOmission`]
+        /// is emitted.
+        channel: ResponseChannel,
+    },
     /// A response message.
     Response {
         /// The ID of the request that produced this response.
         ///
         /// See [`RequestResponse::send_request`].
         request_id: RequestId,
+        /// The protocol negotiated for the request, one of the protocols
+        /// passed to [`RequestResponse::send_request`].
+        protocol: ProtocolInfo,
         /// The response message.
         response: ResponsePayload,
+        /// How the response was received.
+        metadata: ResponseMetadata,
     },
 }
 
+/// How a response was received, e.g. for breaking down the latency of a request.
+#[derive(Debug, Clone)]
+pub struct ResponseMetadata {
+    /// The connection the request was sent over.
+    pub connection_id: ConnectionId,
+    /// The address of the remote peer on that connection.
+    pub remote_address: Multiaddr,
+    /// From sending the request until the handler of the connection picked it up, includes
+    /// dialing the peer when it was not connected yet.
+    pub queued: Duration,
+    /// From the handler asking for the outbound substream until the connection opened it.
+    pub substream_open: Duration,
+    /// Negotiating the protocol of the outbound substream.
+    pub negotiation: Duration,
+    /// From writing the request until the first byte of the response was received,
+    /// `None` for an empty response.
+    pub first_byte: Option<Duration>,
+    /// From sending the request until the whole response was received.
+    pub total: Duration,
+    /// The size of the request payload in bytes. The protocol negotiation and the framing
+    /// added by the [`Codec`], e.g. a length prefix, are not counted.
+    pub payload_bytes_sent: usize,
+    /// The size of the response payload in bytes, not counting the negotiation and the
+    /// framing either.
+    pub payload_bytes_received: usize,
+}
+
+impl ResponseMetadata {
+    fn new(
+        connection: &Connection,
+        request_size: usize,
+        response_size: usize,
+        timings: RequestTimings,
+    ) -> Self {
+        Self {
+            connection_id: connection.id,
+            remote_address: connection.remote_address.clone(),
+            queued: timings.substream_requested - timings.created,
+            substream_open: timings.substream_opened - timings.substream_requested,
+            negotiation: timings.negotiated - timings.substream_opened,
+            first_byte: timings
+                .first_response_byte
+                .map(|first_byte| first_byte - timings.negotiated),
+            total: timings.completed - timings.created,
+            payload_bytes_sent: request_size,
+            payload_bytes_received: response_size,
+        }
+    }
+}
+
 /// The events emitted by a [`RequestResponse`] protocol.
 #[derive(Debug)]
 pub enum RequestResponseEvent {
@@ -75,9 +157,25 @@ pub enum RequestResponseEvent {
     InboundFailure {
         /// The peer from whom the request was received.
         peer: PeerId,
+        /// The protocol negotiated for the request, `None` when the
+        /// request failed before it was received.
+        protocol: Option<ProtocolInfo>,
         /// The error that occurred.
         error: InboundFailure,
     },
+    /// An outbound stream was negotiated.
+    StreamOpened {
+        /// The peer on the other end of the stream.
+        peer: PeerId,
+        /// The ID of the request that opened the stream.
+        ///
+        /// See [`RequestResponse::open_stream`].
+        request_id: RequestId,
+        /// The negotiated substream.
+        stream: NegotiatedSubstream,
+        /// Keeps the underlying connection alive until dropped.
+        keep_alive: Arc<()>,
+    },
 }
 
 /// Possible failures occurring in the context of sending
@@ -98,6 +196,9 @@ pub enum OutboundFailure {
     ConnectionClosed,
     /// The remote supports none of the requested protocols.
     UnsupportedProtocols,
+    /// The response exceeds the maximum response size, see
+    /// [`RequestResponseConfig::max_response_size`].
+    ResponseTooLarge,
 }
 
 impl fmt::Display for OutboundFailure {
@@ -111,6 +212,9 @@ impl fmt::Display for OutboundFailure {
             OutboundFailure::UnsupportedProtocols => {
                 write!(f, "The remote supports none of the requested protocols")
             }
+            OutboundFailure::ResponseTooLarge => {
+                write!(f, "The response exceeds the maximum response size")
+            }
         }
     }
 }
@@ -128,7 +232,14 @@ pub enum InboundFailure {
     Timeout,
     /// The local peer supports none of the protocols requested
     /// by the remote.
-    UnsupportedProtocols,
+    UnsupportedProtocols {
+        /// The protocols proposed by the remote, if we could observe them.
+        requested: Vec<String>,
+    },
+    /// The local peer failed to respond to an inbound request
+    /// due to the [`ResponseChannel`] being dropped instead of
+    /// being passed to [`ResponseChannel::send`].
+    ResponseOmission,
 }
 
 impl fmt::Display for InboundFailure {
@@ -137,16 +248,52 @@ impl fmt::Display for InboundFailure {
             InboundFailure::Timeout => {
                 write!(f, "Timeout while receiving request or sending response")
             }
-            InboundFailure::UnsupportedProtocols => write!(
+            InboundFailure::UnsupportedProtocols { requested } if requested.is_empty() => write!(
                 f,
                 "The local peer supports none of the protocols requested by the remote"
             ),
+            InboundFailure::UnsupportedProtocols { requested } => write!(
+                f,
+                "The local peer supports none of the protocols requested by the remote: {}",
+                requested.join(", ")
+            ),
+            InboundFailure::ResponseOmission => write!(
+                f,
+                "The response channel was dropped without sending a response to the remote"
+            ),
         }
     }
 }
 
 impl std::error::Error for InboundFailure {}
 
+/// A channel for sending a response to an inbound request.
+///
+/// See [`ResponseChannel::send`].
+#[derive(Debug)]
+pub struct ResponseChannel {
+    sender: oneshot::Sender<ResponsePayload>,
+}
+
+impl ResponseChannel {
+    /// Checks whether the response channel is still open, i.e.
+    /// the `RequestResponse` behaviour is still waiting for a
+    /// response to be sent and the connection to the peer
+    /// is still open.
+    pub fn is_open(&self) -> bool {
+        !self.sender.is_canceled()
+    }
+
+    /// Sends the response for the inbound request.
+    ///
+    /// The response is sent on the substream the request was received on.
+    /// If the response channel is no longer open, e.g. the request timed out
+    /// or the connection closed, the given response is returned back.
+    pub fn send(self, response: ResponsePayload) -> Result<(), ResponsePayload> {
+        self.sender.send(response)
+    }
+}
+
 /// The ID of an inbound or outbound request.
 ///
 /// Note: [`RequestId`]'s uniqueness is only guaranteed between two
@@ -155,7 +302,7 @@ impl std::error::Error for InboundFailure {}
 /// [`RequestId`]s nor in a set of inbound or outbound requests
 /// originating from different [`RequestResponse`] behaviours.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub struct RequestId(u64);
+pub struct RequestId(pub(super) u64);
 
 impl fmt::Display for RequestId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -168,6 +315,10 @@ impl fmt::Display for RequestId {
 pub struct RequestResponseConfig {
     pub request_timeout: Duration,
     pub connection_keep_alive: Duration,
+    /// The maximum size of a response in bytes, longer responses fail with
+    /// [`OutboundFailure::ResponseTooLarge`]. Can be overridden per request,
+    /// see [`RequestOptions`].
+    pub max_response_size: usize,
 }
 
 impl Default for RequestResponseConfig {
@@ -175,22 +326,50 @@ impl Default for RequestResponseConfig {
         Self {
             connection_keep_alive: Duration::from_secs(10),
             request_timeout: Duration::from_secs(10),
+            max_response_size: 10 * 1024 * 1024,
         }
     }
 }
 
+/// Options overriding the [`RequestResponseConfig`] for a single request.
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    /// The time allowed for negotiating the protocol, sending the request and
+    /// receiving the response, see [`RequestResponseConfig::request_timeout`].
+    pub timeout: Option<Duration>,
+    /// The request fails with [`OutboundFailure::Timeout`] when not completed by this
+    /// time, regardless of the `timeout`.
+    pub deadline: Option<Instant>,
+    /// The maximum size of the response in bytes.
+    pub max_response_size: Option<usize>,
+    /// Send the request again when it fails for a transient reason.
+    ///
+    /// Applied by [`super::PeerNode`], `RequestResponse` sends each request once.
+    pub retry: Option<RetryPolicy>,
+}
+
 /// A request/response protocol for some message codec.
 pub struct RequestResponse {
+    /// The supported inbound protocols.
+    inbound_protocols: InboundProtocols,
+    /// The codecs of the protocols, see [`RequestResponse::set_codec`].
+    codecs: Codecs,
+    /// The inbound protocols rejected during negotiation, recorded by the transport
+    /// for each connection.
+    rejected_protocols: RejectedProtocols,
     /// The next (local) request ID.
     next_request_id: RequestId,
+    /// The next (inbound) request ID.
+    next_inbound_id: Arc<AtomicU64>,
     /// The protocol configuration.
     config: RequestResponseConfig,
     /// Pending events to return from `poll`.
-    pending_events: VecDeque<NetworkBehaviourAction<RequestResponseEvent, RequestResponseHandler>>,
+    pending_events:
+        VecDeque<NetworkBehaviourAction<RequestResponseEvent, RequestResponseHandlerProto>>,
     /// The currently connected peers, their pending outbound and inbound responses and their known,
     /// reachable addresses, if any.
     connected: HashMap<PeerId, SmallVec<[Connection; 2]>>,
-    /// Externally managed addresses via `add_address` and `remove_address`.
+    /// Externally managed addresses via `add_address`.
     addresses: HashMap<PeerId, SmallVec<[Multiaddr; 6]>>,
     /// Requests that have not yet been sent and are waiting for a connection
     /// to be established.
@@ -202,7 +381,11 @@ impl RequestResponse {
     /// codec and configuration.
     pub fn new(cfg: RequestResponseConfig) -> Self {
         RequestResponse {
+            inbound_protocols: Default::default(),
+            codecs: Default::default(),
+            rejected_protocols: Default::default(),
             next_request_id: RequestId(1),
+            next_inbound_id: Arc::new(AtomicU64::new(1)),
             config: cfg,
             pending_events: VecDeque::new(),
             connected: HashMap::new(),
@@ -221,19 +404,54 @@ impl RequestResponse {
     /// > the `RequestResonse` protocol must either be embedded
     /// > in another `NetworkBehaviour` that provides peer and
     /// > address discovery, or known addresses of peers must be
-    /// > managed via [`RequestResponse::add_address`] and
-    /// > [`RequestResponse::remove_address`].
+    /// > managed via [`RequestResponse::add_address`].
+    ///
+    /// The given options override the configuration for this request.
     pub fn send_request(
         &mut self,
         peer: &PeerId,
         protocols: &[ProtocolInfo],
         request: RequestPayload,
+        options: RequestOptions,
+    ) -> RequestId {
+        let kind = RequestKind::Request {
+            payload: request,
+            max_response_size: options
+                .max_response_size
+                .unwrap_or(self.config.max_response_size),
+        };
+        self.enqueue_request(peer, protocols, kind, &options)
+    }
+
+    /// Initiates opening an outbound stream for one of the given protocols.
+    ///
+    /// The negotiated substream is reported via [`RequestResponseEvent::StreamOpened`],
+    /// failures are reported via [`RequestResponseEvent::OutboundFailure`] in the same
+    /// way as for requests sent by [`RequestResponse::send_request`].
+    pub fn open_stream(&mut self, peer: &PeerId, protocols: &[ProtocolInfo]) -> RequestId {
+        self.enqueue_request(peer, protocols, RequestKind::Stream, &Default::default())
+    }
+
+    /// Sends the request immediately if the peer is connected, otherwise
+    /// queues it and initiates a dialing attempt.
+    fn enqueue_request(
+        &mut self,
+        peer: &PeerId,
+        protocols: &[ProtocolInfo],
+        kind: RequestKind,
+        options: &RequestOptions,
     ) -> RequestId {
         let request_id = self.next_request_id();
         let request = RequestProtocol {
             request_id,
             protocols: protocols.into(),
-            payload: request,
+            kind,
+            codecs: self.codecs.clone(),
+            timeout: options.timeout.unwrap_or(self.config.request_timeout),
+            deadline: options.deadline,
+            created: Instant::now(),
+            substream_requested: None,
+            substream_opened: Cell::new(None),
         };
 
         if let Some(request) = self.try_send_request(peer, request) {
@@ -251,60 +469,64 @@ impl RequestResponse {
         request_id
     }
 
-    /// Adds a known address for a peer that can be used for
-    /// dialing attempts by the `Swarm`, i.e. is returned
-    /// by [`NetworkBehaviour::addresses_of_peer`].
-    ///
-    /// Addresses added in this way are only removed by `remove_address`.
-    pub fn add_address(&mut self, peer: &PeerId, address: Multiaddr) {
-        self.addresses.entry(*peer).or_default().push(address);
+    /// Drops a request still waiting for a connection to the peer, the request is not
+    /// reported anymore. Requests sent over a connection already fail once their
+    /// timeout or deadline elapses.
+    pub fn cancel_pending_request(&mut self, peer: &PeerId, request_id: RequestId) {
+        if let Some(requests) = self.pending_outbound_requests.get_mut(peer) {
+            requests.retain(|request| request.request_id != request_id);
+            if requests.is_empty() {
+                self.pending_outbound_requests.remove(peer);
+            }
+        }
     }
 
-    /// Removes an address of a peer previously added via `add_address`.
-    #[allow(dead_code)]
-    pub fn remove_address(&mut self, peer: &PeerId, address: &Multiaddr) {
-        let mut last = false;
-        if let Some(addresses) = self.addresses.get_mut(peer) {
-            addresses.retain(|a| a != address);
-            last = addresses.is_empty();
-        }
-        if last {
-            self.addresses.remove(peer);
+    /// Starts accepting inbound requests for the given protocol.
+    ///
+    /// Inbound requests are reported as [`RequestResponseMessage::Request`].
+    /// Requests for protocols that were not registered are rejected during
+    /// protocol negotiation.
+    pub fn register_protocol(&mut self, protocol: ProtocolInfo) {
+        let mut protocols = self
+            .inbound_protocols
+            .write()
+            .expect("Inbound protocols lock should not be poisoned.");
+        if !protocols.contains(&protocol) {
+            protocols.push(protocol);
         }
     }
 
-    /// Checks whether a peer is currently connected.
-    #[allow(dead_code)]
-    pub fn is_connected(&self, peer: &PeerId) -> bool {
-        if let Some(connections) = self.connected.get(peer) {
-            !connections.is_empty()
-        } else {
-            false
-        }
+    /// Sets the codec framing the requests and responses of the given protocol, both
+    /// inbound and outbound. Protocols use [`CloseDelimited`] by default.
+    pub fn set_codec(&mut self, protocol: ProtocolInfo, codec: Arc<dyn Codec>) {
+        self.codecs
+            .write()
+            .expect("Codecs lock should not be poisoned.")
+            .insert(protocol, codec);
     }
 
-    /// Checks whether an outbound request to the peer with the provided
-    /// [`PeerId`] initiated by [`RequestResponse::send_request`] is still
-    /// pending, i.e. waiting for a response.
-    #[allow(dead_code)]
-    pub fn is_pending_outbound(&self, peer: &PeerId, request_id: &RequestId) -> bool {
-        // Check if request is already sent on established connection.
-        let est_conn = self
-            .connected
-            .get(peer)
-            .map(|cs| {
-                cs.iter()
-                    .any(|c| c.pending_inbound_responses.contains(request_id))
-            })
-            .unwrap_or(false);
-        // Check if request is still pending to be sent.
-        let pen_conn = self
-            .pending_outbound_requests
-            .get(peer)
-            .map(|rps| rps.iter().any(|rp| rp.request_id == *request_id))
-            .unwrap_or(false);
-
-        est_conn || pen_conn
+    /// Returns the codec framing the requests and responses of the given protocol.
+    pub fn codec(&self, protocol: &[u8]) -> Arc<dyn Codec> {
+        codec_of(&self.codecs, protocol)
+    }
+
+    /// Returns the record of rejected inbound protocols.
+    ///
+    /// The transport must record the protocols rejected during negotiation
+    /// here, see [`super::negotiation::RecordingMuxer`], otherwise
+    /// [`InboundFailure::UnsupportedProtocols`] cannot tell what the remote requested.
+    pub fn rejected_protocols(&self) -> RejectedProtocols {
+        self.rejected_protocols.clone()
+    }
+
+    /// Adds a known address for a peer that can be used for
+    /// dialing attempts by the `Swarm`, i.e. is returned
+    /// by [`NetworkBehaviour::addresses_of_peer`].
+    pub fn add_address(&mut self, peer: &PeerId, address: Multiaddr) {
+        let addresses = self.addresses.entry(*peer).or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
     }
 
     /// Returns the next request ID.
@@ -382,6 +604,7 @@ impl RequestResponse {
             ConnectedPoint::Dialer { address, .. } => Some(address.clone()),
             ConnectedPoint::Listener { .. } => None,
         };
+        let new_remote_address = new.get_remote_address().clone();
         let connections = self
             .connected
             .get_mut(&peer_id)
@@ -392,6 +615,7 @@ impl RequestResponse {
             .find(|c| c.id == connection_id)
             .expect("Address change can only happen on an established connection.");
         connection.address = new_address;
+        connection.remote_address = new_remote_address;
     }
 
     fn on_connection_established(
@@ -411,7 +635,11 @@ impl RequestResponse {
         self.connected
             .entry(peer_id)
             .or_default()
-            .push(Connection::new(connection_id, address));
+            .push(Connection::new(
+                connection_id,
+                address,
+                endpoint.get_remote_address().clone(),
+            ));
 
         if other_established == 0 {
             if let Some(pending) = self.pending_outbound_requests.remove(&peer_id) {
@@ -488,14 +716,18 @@ impl RequestResponse {
 }
 
 impl NetworkBehaviour for RequestResponse {
-    type ConnectionHandler = RequestResponseHandler;
+    type ConnectionHandler = RequestResponseHandlerProto;
     type OutEvent = RequestResponseEvent;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        RequestResponseHandler::new(
+        let handler = RequestResponseHandler::new(
+            self.inbound_protocols.clone(),
+            self.codecs.clone(),
             self.config.connection_keep_alive,
             self.config.request_timeout,
-        )
+            self.next_inbound_id.clone(),
+        );
+        RequestResponseHandlerProto::new(handler, self.rejected_protocols.clone())
     }
 
     fn addresses_of_peer(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
@@ -538,25 +770,86 @@ impl NetworkBehaviour for RequestResponse {
             libp2p::swarm::ConnectionHandler>::OutEvent,
     ) {
         match event {
+            RequestResponseHandlerEvent::Request {
+                request_id: _,
+                protocol,
+                request,
+                sender,
+            } => {
+                let channel = ResponseChannel { sender };
+                let message = RequestResponseMessage::Request {
+                    protocol,
+                    request,
+                    channel,
+                };
+                self.pending_events
+                    .push_back(NetworkBehaviourAction::GenerateEvent(
+                        RequestResponseEvent::Message { peer, message },
+                    ));
+            }
+            RequestResponseHandlerEvent::ResponseOmission {
+                request_id: _,
+                protocol,
+            } => {
+                self.pending_events
+                    .push_back(NetworkBehaviourAction::GenerateEvent(
+                        RequestResponseEvent::InboundFailure {
+                            peer,
+                            protocol,
+                            error: InboundFailure::ResponseOmission,
+                        },
+                    ));
+            }
             RequestResponseHandlerEvent::Response {
                 request_id,
+                protocol,
                 response,
+                request_size,
+                timings,
             } => {
-                let removed = self.remove_pending_inbound_response(&peer, connection, &request_id);
+                let connection = self
+                    .get_connection_mut(&peer, connection)
+                    .expect("Response can only be received on an established connection.");
+                let removed = connection.pending_inbound_responses.remove(&request_id);
                 debug_assert!(
                     removed,
                     "Expect request_id to be pending before receiving response.",
                 );
+                let metadata =
+                    ResponseMetadata::new(connection, request_size, response.len(), timings);
 
                 let message = RequestResponseMessage::Response {
                     request_id,
+                    protocol,
                     response,
+                    metadata,
                 };
                 self.pending_events
                     .push_back(NetworkBehaviourAction::GenerateEvent(
                         RequestResponseEvent::Message { peer, message },
                     ));
             }
+            RequestResponseHandlerEvent::StreamOpened {
+                request_id,
+                stream,
+                keep_alive,
+            } => {
+                let removed = self.remove_pending_inbound_response(&peer, connection, &request_id);
+                debug_assert!(
+                    removed,
+                    "Expect request_id to be pending before opening the stream.",
+                );
+
+                self.pending_events
+                    .push_back(NetworkBehaviourAction::GenerateEvent(
+                        RequestResponseEvent::StreamOpened {
+                            peer,
+                            request_id,
+                            stream,
+                            keep_alive,
+                        },
+                    ));
+            }
             RequestResponseHandlerEvent::OutboundTimeout(request_id) => {
                 let removed = self.remove_pending_inbound_response(&peer, connection, &request_id);
                 debug_assert!(
@@ -573,7 +866,7 @@ impl NetworkBehaviour for RequestResponse {
                         },
                     ));
             }
-            RequestResponseHandlerEvent::InboundTimeout => {
+            RequestResponseHandlerEvent::InboundTimeout(protocol) => {
                 // Note: `RequestResponseHandlerEvent::InboundTimeout` is emitted both for timing
                 // out to receive the request and for timing out sending the response. In the former
                 // case the request is never added to `pending_outbound_responses` and thus one can
@@ -584,6 +877,7 @@ impl NetworkBehaviour for RequestResponse {
                     .push_back(NetworkBehaviourAction::GenerateEvent(
                         RequestResponseEvent::InboundFailure {
                             peer,
+                            protocol,
                             error: InboundFailure::Timeout,
                         },
                     ));
@@ -604,7 +898,23 @@ impl NetworkBehaviour for RequestResponse {
                         },
                     ));
             }
-            RequestResponseHandlerEvent::InboundUnsupportedProtocols => {
+            RequestResponseHandlerEvent::OutboundResponseTooLarge(request_id) => {
+                let removed = self.remove_pending_inbound_response(&peer, connection, &request_id);
+                debug_assert!(
+                    removed,
+                    "Expect request_id to be pending before receiving the response.",
+                );
+
+                self.pending_events
+                    .push_back(NetworkBehaviourAction::GenerateEvent(
+                        RequestResponseEvent::OutboundFailure {
+                            peer,
+                            request_id,
+                            error: OutboundFailure::ResponseTooLarge,
+                        },
+                    ));
+            }
+            RequestResponseHandlerEvent::InboundUnsupportedProtocols(requested) => {
                 // Note: No need to call `self.remove_pending_outbound_response`,
                 // `RequestResponseHandlerEvent::Request` was never emitted for this request and
                 // thus request was never added to `pending_outbound_responses`.
@@ -612,7 +922,8 @@ impl NetworkBehaviour for RequestResponse {
                     .push_back(NetworkBehaviourAction::GenerateEvent(
                         RequestResponseEvent::InboundFailure {
                             peer,
-                            error: InboundFailure::UnsupportedProtocols,
+                            protocol: None,
+                            error: InboundFailure::UnsupportedProtocols { requested },
                         },
                     ));
             }
@@ -644,16 +955,19 @@ pub const EMPTY_QUEUE_SHRINK_THRESHOLD: usize = 100;
 struct Connection {
     id: ConnectionId,
     address: Option<Multiaddr>,
+    /// The address of the remote peer, also for inbound connections.
+    remote_address: Multiaddr,
     /// Pending inbound responses for previously sent requests on this
     /// connection.
     pending_inbound_responses: HashSet<RequestId>,
 }
 
 impl Connection {
-    fn new(id: ConnectionId, address: Option<Multiaddr>) -> Self {
+    fn new(id: ConnectionId, address: Option<Multiaddr>, remote_address: Multiaddr) -> Self {
         Self {
             id,
             address,
+            remote_address,
             pending_inbound_responses: Default::default(),
         }
     }
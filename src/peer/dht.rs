@@ -0,0 +1,70 @@
+//! Finding peers and content providers via the Kademlia DHT, see [`super::PeerNodeConfig::dht`].
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::kad::record::store::MemoryStore;
+use libp2p::kad::{Kademlia, KademliaConfig};
+
+/// The Kademlia behaviour of a [`super::PeerNode`].
+pub(super) type Dht = Kademlia<MemoryStore>;
+
+/// The configuration of the Kademlia DHT.
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// The name of the Kademlia protocol, nodes using different names form separate DHTs.
+    pub protocol_name: Vec<u8>,
+    /// The peers to join the DHT through. The node looks itself up via these peers
+    /// on start to fill its routing table.
+    pub bootstrap_peers: Vec<(PeerId, Multiaddr)>,
+    /// How long a DHT lookup may take.
+    pub query_timeout: Duration,
+    /// How long the provider records we publish are kept by other peers, `None` keeps
+    /// them forever. See [`super::PeerNode::start_providing`].
+    pub provider_record_ttl: Option<Duration>,
+    /// How often we publish our provider records again, it must be shorter than
+    /// the `provider_record_ttl` to keep the records alive. `None` disables republishing.
+    pub provider_publication_interval: Option<Duration>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            protocol_name: b"/ipfs/kad/1.0.0".to_vec(),
+            bootstrap_peers: Vec::new(),
+            query_timeout: Duration::from_secs(60),
+            provider_record_ttl: Some(Duration::from_secs(24 * 60 * 60)),
+            provider_publication_interval: Some(Duration::from_secs(12 * 60 * 60)),
+        }
+    }
+}
+
+impl DhtConfig {
+    pub(super) fn into_behaviour(self, peer_id: PeerId) -> Dht {
+        let mut config = KademliaConfig::default();
+        config
+            .set_protocol_names(vec![Cow::Owned(self.protocol_name)])
+            .set_query_timeout(self.query_timeout)
+            .set_provider_record_ttl(self.provider_record_ttl)
+            .set_provider_publication_interval(self.provider_publication_interval);
+
+        let mut kademlia = Kademlia::with_config(peer_id, MemoryStore::new(peer_id), config);
+        for (peer_id, addr) in &self.bootstrap_peers {
+            kademlia.add_address(peer_id, addr.clone());
+        }
+        if !self.bootstrap_peers.is_empty() {
+            // Cannot fail, the routing table contains the bootstrap peers.
+            let _ = kademlia.bootstrap();
+        }
+        kademlia
+    }
+}
+
+/// A peer providing a key, see [`super::PeerNode::find_providers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provider {
+    pub peer_id: PeerId,
+    /// The addresses to dial the provider at, empty when none are known.
+    pub addresses: Vec<Multiaddr>,
+}
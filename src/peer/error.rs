@@ -0,0 +1,463 @@
+//! Errors reported by [`super::PeerNode`] operations.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::ping;
+use libp2p::swarm::DialError;
+use libp2p::TransportError;
+
+use super::behaviour::{OutboundFailure, ProtocolInfo};
+
+/// An error returned by a [`super::PeerNode`] operation.
+///
+/// The errors are cheap to clone and don't hold on to any libp2p internals, use
+/// [`PeerNodeError::code`] to map them to errors visible to JavaScript. The variants
+/// carrying the most details are boxed, keeping the `Result`s of all operations small.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerNodeError {
+    /// The peer could not be dialed.
+    Dial(Box<DialFailure>),
+    /// The peer could not be dialed through the relay, see `/p2p-circuit` addresses.
+    RelayedDial(Box<RelayedDialFailure>),
+    /// The remote peer did not respond in time.
+    Timeout { peer_id: PeerId, protocol: String },
+    /// The remote peer supports none of the requested protocols.
+    UnsupportedProtocols {
+        peer_id: PeerId,
+        protocols: Vec<String>,
+    },
+    /// The connection was closed before the operation finished.
+    ConnectionClosed { peer_id: PeerId, protocol: String },
+    /// The codec of the protocol cannot send the request payload, e.g. the payload doesn't
+    /// have the size required by [`super::FixedLength`]. The request was not sent.
+    InvalidPayload { protocol: String, message: String },
+    /// The remote peer would take a probe of the protocol for an empty request, its requests
+    /// are [`super::CloseDelimited`], see [`super::PeerNode::supports`]. Nothing was sent.
+    UnprobeableProtocol { protocol: String },
+    /// The response sent by the remote peer exceeds the size limit.
+    ResponseTooLarge { peer_id: PeerId, protocol: String },
+    /// The stream was closed, either locally or because the connection was closed.
+    StreamClosed { peer_id: PeerId, protocol: String },
+    /// Reading from or writing to the stream failed.
+    Stream(Box<StreamFailure>),
+    /// The node cannot listen on the address.
+    Listen {
+        address: Multiaddr,
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// The operation needs the DHT, which is not enabled, see
+    /// [`super::PeerNodeConfig::dht`].
+    DhtDisabled,
+    /// The DHT operation failed.
+    Dht { message: String },
+    /// The configuration of the operation is not valid, e.g. a zero interval.
+    InvalidConfig { message: String },
+    /// The node is shutting down or has been shut down already.
+    ShuttingDown,
+    /// The event loop of the node stopped unexpectedly, e.g. after a panic.
+    EventLoopFailed { message: String },
+    /// The request failed after it was sent more than once, see
+    /// [`super::RequestOptions::retry`]. The error of the last attempt tells why.
+    RetryFailed {
+        attempts: u32,
+        last_error: Box<PeerNodeError>,
+    },
+}
+
+/// Why a peer could not be dialed, see [`PeerNodeError::Dial`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialFailure {
+    pub peer_id: PeerId,
+    /// The protocol of the request or stream we dialed the peer for, `None` when dialing
+    /// was the whole operation, see [`super::PeerNode::dial`].
+    pub protocol: Option<String>,
+    /// The failures of the individual addresses we tried to dial, empty when the dial
+    /// failed before any address was tried (e.g. the peer has no known addresses).
+    pub addresses: Vec<TransportFailure>,
+    pub message: String,
+}
+
+/// Why a peer could not be dialed through a relay, see [`PeerNodeError::RelayedDial`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayedDialFailure {
+    pub peer_id: PeerId,
+    /// The protocol we dialed the peer for, see [`DialFailure::protocol`].
+    pub protocol: Option<String>,
+    pub relay_peer_id: PeerId,
+    /// Which part of the relayed connection failed.
+    pub hop: RelayHop,
+    pub message: String,
+}
+
+/// Why reading from or writing to a stream failed, see [`PeerNodeError::Stream`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamFailure {
+    pub peer_id: PeerId,
+    pub protocol: String,
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+/// Why dialing a particular address failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportFailure {
+    pub address: Multiaddr,
+    /// The kind of the underlying I/O error, e.g. [`io::ErrorKind::ConnectionRefused`].
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+/// A part of a connection relayed through a circuit relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayHop {
+    /// We could not connect to the relay.
+    Relay,
+    /// We are connected to the relay, but the relay did not connect us to the destination
+    /// peer, e.g. because the peer has no reservation on the relay or the relay refused
+    /// to open the circuit.
+    Destination,
+}
+
+impl PeerNodeError {
+    /// A stable identifier of the error, e.g. `ERR_TIMEOUT`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PeerNodeError::Dial(failure) => {
+                let refused = !failure.addresses.is_empty()
+                    && failure
+                        .addresses
+                        .iter()
+                        .all(|a| a.kind == io::ErrorKind::ConnectionRefused);
+                if refused {
+                    "ERR_CONNECTION_REFUSED"
+                } else {
+                    "ERR_DIAL_FAILED"
+                }
+            }
+            PeerNodeError::RelayedDial(failure) => match failure.hop {
+                RelayHop::Relay => "ERR_RELAY_UNREACHABLE",
+                RelayHop::Destination => "ERR_RELAY_DESTINATION_UNREACHABLE",
+            },
+            PeerNodeError::Timeout { .. } => "ERR_TIMEOUT",
+            PeerNodeError::UnsupportedProtocols { .. } => "ERR_UNSUPPORTED_PROTOCOLS",
+            PeerNodeError::ConnectionClosed { .. } => "ERR_CONNECTION_CLOSED",
+            PeerNodeError::InvalidPayload { .. } => "ERR_INVALID_PAYLOAD",
+            PeerNodeError::UnprobeableProtocol { .. } => "ERR_UNPROBEABLE_PROTOCOL",
+            PeerNodeError::ResponseTooLarge { .. } => "ERR_RESPONSE_TOO_LARGE",
+            PeerNodeError::StreamClosed { .. } => "ERR_STREAM_CLOSED",
+            PeerNodeError::Stream(_) => "ERR_STREAM",
+            PeerNodeError::Listen { .. } => "ERR_LISTEN_FAILED",
+            PeerNodeError::DhtDisabled => "ERR_DHT_DISABLED",
+            PeerNodeError::Dht { .. } => "ERR_DHT",
+            PeerNodeError::InvalidConfig { .. } => "ERR_INVALID_CONFIG",
+            PeerNodeError::ShuttingDown => "ERR_SHUTTING_DOWN",
+            PeerNodeError::EventLoopFailed { .. } => "ERR_EVENT_LOOP_FAILED",
+            PeerNodeError::RetryFailed { last_error, .. } => last_error.code(),
+        }
+    }
+
+    /// The remote peer the failed operation was talking to, if any.
+    pub fn peer_id(&self) -> Option<PeerId> {
+        match self {
+            PeerNodeError::Dial(failure) => Some(failure.peer_id),
+            PeerNodeError::RelayedDial(failure) => Some(failure.peer_id),
+            PeerNodeError::Stream(failure) => Some(failure.peer_id),
+            PeerNodeError::Timeout { peer_id, .. }
+            | PeerNodeError::UnsupportedProtocols { peer_id, .. }
+            | PeerNodeError::ConnectionClosed { peer_id, .. }
+            | PeerNodeError::ResponseTooLarge { peer_id, .. }
+            | PeerNodeError::StreamClosed { peer_id, .. } => Some(*peer_id),
+            PeerNodeError::InvalidPayload { .. }
+            | PeerNodeError::UnprobeableProtocol { .. }
+            | PeerNodeError::Listen { .. }
+            | PeerNodeError::DhtDisabled
+            | PeerNodeError::Dht { .. }
+            | PeerNodeError::InvalidConfig { .. }
+            | PeerNodeError::ShuttingDown
+            | PeerNodeError::EventLoopFailed { .. } => None,
+            PeerNodeError::RetryFailed { last_error, .. } => last_error.peer_id(),
+        }
+    }
+
+    pub(super) fn dial(peer_id: PeerId, error: &DialError) -> Self {
+        PeerNodeError::Dial(Box::new(DialFailure {
+            peer_id,
+            protocol: None,
+            addresses: TransportFailure::from_dial_error(error),
+            message: error.to_string(),
+        }))
+    }
+
+    pub(super) fn relayed_dial(
+        peer_id: PeerId,
+        relay_peer_id: PeerId,
+        hop: RelayHop,
+        error: &DialError,
+    ) -> Self {
+        PeerNodeError::RelayedDial(Box::new(RelayedDialFailure {
+            peer_id,
+            protocol: None,
+            relay_peer_id,
+            hop,
+            message: error.to_string(),
+        }))
+    }
+
+    /// Tells which protocols the peer was dialed for when dialing it failed.
+    pub(super) fn for_protocols(mut self, protocols: &[ProtocolInfo]) -> Self {
+        match &mut self {
+            PeerNodeError::Dial(failure) => {
+                failure.protocol = Some(describe_protocols(protocols));
+            }
+            PeerNodeError::RelayedDial(failure) => {
+                failure.protocol = Some(describe_protocols(protocols));
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// The error of a request or stream for any of the given protocols.
+    pub(super) fn outbound(
+        peer_id: PeerId,
+        protocols: &[ProtocolInfo],
+        error: OutboundFailure,
+    ) -> Self {
+        let protocol = describe_protocols(protocols);
+        match error {
+            OutboundFailure::DialFailure => PeerNodeError::Dial(Box::new(DialFailure {
+                peer_id,
+                protocol: Some(protocol),
+                addresses: Vec::new(),
+                message: error.to_string(),
+            })),
+            OutboundFailure::Timeout => PeerNodeError::Timeout { peer_id, protocol },
+            OutboundFailure::ConnectionClosed => {
+                PeerNodeError::ConnectionClosed { peer_id, protocol }
+            }
+            OutboundFailure::UnsupportedProtocols => PeerNodeError::UnsupportedProtocols {
+                peer_id,
+                protocols: protocols.iter().map(|p| protocol_name(p)).collect(),
+            },
+            OutboundFailure::ResponseTooLarge => {
+                PeerNodeError::ResponseTooLarge { peer_id, protocol }
+            }
+        }
+    }
+
+    pub(super) fn timeout(peer_id: PeerId, protocols: &[ProtocolInfo]) -> Self {
+        PeerNodeError::Timeout {
+            peer_id,
+            protocol: describe_protocols(protocols),
+        }
+    }
+
+    pub(super) fn ping(peer_id: PeerId, failure: &ping::Failure) -> Self {
+        let protocol = protocol_name(ping::PROTOCOL_NAME);
+        match failure {
+            ping::Failure::Timeout => PeerNodeError::Timeout { peer_id, protocol },
+            ping::Failure::Unsupported => PeerNodeError::UnsupportedProtocols {
+                peer_id,
+                protocols: vec![protocol],
+            },
+            ping::Failure::Other { error } => PeerNodeError::Stream(Box::new(StreamFailure {
+                peer_id,
+                protocol,
+                kind: io::ErrorKind::Other,
+                message: error.to_string(),
+            })),
+        }
+    }
+
+    pub(super) fn stream(peer_id: PeerId, protocol: &[u8], error: io::Error) -> Self {
+        let protocol = protocol_name(protocol);
+        match error.kind() {
+            io::ErrorKind::NotConnected => PeerNodeError::StreamClosed { peer_id, protocol },
+            kind => PeerNodeError::Stream(Box::new(StreamFailure {
+                peer_id,
+                protocol,
+                kind,
+                message: error.to_string(),
+            })),
+        }
+    }
+
+    pub(super) fn invalid_payload(protocol: &[u8], error: &io::Error) -> Self {
+        PeerNodeError::InvalidPayload {
+            protocol: protocol_name(protocol),
+            message: error.to_string(),
+        }
+    }
+
+    pub(super) fn unprobeable_protocol(protocol: &[u8]) -> Self {
+        PeerNodeError::UnprobeableProtocol {
+            protocol: protocol_name(protocol),
+        }
+    }
+
+    pub(super) fn listen(address: Multiaddr, error: &io::Error) -> Self {
+        let (kind, message) = describe_io_error(error);
+        PeerNodeError::Listen {
+            address,
+            kind,
+            message,
+        }
+    }
+}
+
+impl TransportFailure {
+    /// Returns the failures of the individual addresses tried by the dial.
+    pub(super) fn from_dial_error(error: &DialError) -> Vec<Self> {
+        match error {
+            DialError::Transport(errors) => errors
+                .iter()
+                .map(|(address, error)| {
+                    let (kind, message) = match error {
+                        TransportError::MultiaddrNotSupported(_) => {
+                            (io::ErrorKind::Unsupported, error.to_string())
+                        }
+                        TransportError::Other(err) => describe_io_error(err),
+                    };
+                    TransportFailure {
+                        address: address.clone(),
+                        kind,
+                        message,
+                    }
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for PeerNodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerNodeError::Dial(failure) => {
+                let DialFailure {
+                    peer_id,
+                    protocol,
+                    message,
+                    ..
+                } = &**failure;
+                write!(f, "Cannot dial peer {peer_id}")?;
+                if let Some(protocol) = protocol {
+                    write!(f, " for {protocol}")?;
+                }
+                write!(f, ": {message}")
+            }
+            PeerNodeError::RelayedDial(failure) => {
+                let RelayedDialFailure {
+                    peer_id,
+                    protocol,
+                    relay_peer_id,
+                    hop,
+                    message,
+                } = &**failure;
+                write!(f, "Cannot dial peer {peer_id}")?;
+                if let Some(protocol) = protocol {
+                    write!(f, " for {protocol}")?;
+                }
+                match hop {
+                    RelayHop::Relay => {
+                        write!(f, ", relay {relay_peer_id} is not reachable: {message}")
+                    }
+                    RelayHop::Destination => write!(
+                        f,
+                        ", relay {relay_peer_id} did not connect us to the peer: {message}"
+                    ),
+                }
+            }
+            PeerNodeError::Timeout { peer_id, protocol } => write!(
+                f,
+                "Timeout while waiting for a {protocol} response from peer {peer_id}"
+            ),
+            PeerNodeError::UnsupportedProtocols { peer_id, protocols } => write!(
+                f,
+                "Peer {peer_id} supports none of the requested protocols: {}",
+                protocols.join(", ")
+            ),
+            PeerNodeError::ConnectionClosed { peer_id, protocol } => write!(
+                f,
+                "Connection to peer {peer_id} was closed before a {protocol} response was received"
+            ),
+            PeerNodeError::InvalidPayload { protocol, message } => {
+                write!(f, "Cannot send the {protocol} request: {message}")
+            }
+            PeerNodeError::UnprobeableProtocol { protocol } => write!(
+                f,
+                "Cannot probe {protocol}, the remote peer would take the probe for an empty request"
+            ),
+            PeerNodeError::ResponseTooLarge { peer_id, protocol } => write!(
+                f,
+                "The {protocol} response from peer {peer_id} exceeds the size limit"
+            ),
+            PeerNodeError::StreamClosed { peer_id, protocol } => {
+                write!(f, "The {protocol} stream to peer {peer_id} was closed")
+            }
+            PeerNodeError::Stream(failure) => write!(
+                f,
+                "The {} stream to peer {} failed: {}",
+                failure.protocol, failure.peer_id, failure.message
+            ),
+            PeerNodeError::Listen {
+                address, message, ..
+            } => write!(f, "Cannot listen on {address}: {message}"),
+            PeerNodeError::DhtDisabled => write!(f, "The DHT is not enabled"),
+            PeerNodeError::Dht { message } => write!(f, "The DHT operation failed: {message}"),
+            PeerNodeError::InvalidConfig { message } => {
+                write!(f, "Invalid configuration: {message}")
+            }
+            PeerNodeError::ShuttingDown => write!(f, "The peer node is shutting down"),
+            PeerNodeError::EventLoopFailed { message } => {
+                write!(f, "The event loop of the peer node failed: {message}")
+            }
+            PeerNodeError::RetryFailed {
+                attempts,
+                last_error,
+            } => write!(f, "{last_error} (after {attempts} attempts)"),
+        }
+    }
+}
+
+impl Error for PeerNodeError {}
+
+fn protocol_name(protocol: &[u8]) -> String {
+    String::from_utf8_lossy(protocol).into_owned()
+}
+
+/// Describes the protocols offered to the remote peer, e.g. `/foo/2.0.0 or /foo/1.0.0`.
+fn describe_protocols(protocols: &[ProtocolInfo]) -> String {
+    protocols
+        .iter()
+        .map(|p| protocol_name(p))
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+/// Returns the most specific kind and message found in the chain of the error sources.
+///
+/// Transport errors wrap the OS error in several layers of upgrade errors, the outer layers
+/// all report [`io::ErrorKind::Other`].
+fn describe_io_error(error: &io::Error) -> (io::ErrorKind, String) {
+    let mut kind = error.kind();
+    let mut message = error.to_string();
+    let mut source = error.get_ref().map(|e| e as &(dyn Error + 'static));
+    while let Some(err) = source {
+        match err.downcast_ref::<io::Error>() {
+            Some(io_err) => {
+                if io_err.kind() != io::ErrorKind::Other {
+                    kind = io_err.kind();
+                    message = io_err.to_string();
+                }
+                source = io_err.get_ref().map(|e| e as &(dyn Error + 'static));
+            }
+            None => source = err.source(),
+        }
+    }
+    (kind, message)
+}
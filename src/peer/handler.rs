@@ -22,37 +22,60 @@
 
 mod protocol;
 
-pub use self::protocol::{ProtocolInfo, ProtocolName, RequestPayload, ResponsePayload};
+pub use self::protocol::{
+    codec_of, CloseDelimited, Codec, Codecs, FixedLength, LengthPrefixed, ProtocolInfo,
+    RequestKind, RequestPayload, ResponsePayload,
+};
 
 use super::behaviour::{RequestId, EMPTY_QUEUE_SHRINK_THRESHOLD};
+use super::negotiation::{InboundSubstreams, RejectedProtocols, SubstreamRejections};
 
 use libp2p::swarm::handler::{
-    ConnectionEvent, DialUpgradeError, FullyNegotiatedOutbound, ListenUpgradeError,
+    ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+    ListenUpgradeError,
 };
-pub use protocol::RequestProtocol;
+use protocol::{PayloadTooLarge, RequestOutput};
+pub use protocol::{RequestProtocol, RequestTimings, ResponseProtocol};
 
-use libp2p::core::upgrade::{DeniedUpgrade, NegotiationError, UpgradeError};
+use libp2p::core::upgrade::{NegotiationError, UpgradeError};
+use libp2p::core::{ConnectedPoint, PeerId};
+use libp2p::futures::{channel::oneshot, future::BoxFuture, prelude::*, stream::FuturesUnordered};
 use libp2p::swarm::{
     handler::{ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, KeepAlive},
-    SubstreamProtocol,
+    IntoConnectionHandler, NegotiatedSubstream, SubstreamProtocol,
 };
+use smallvec::SmallVec;
 
 use std::time::Instant;
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, Weak,
+    },
     task::{Context, Poll},
     time::Duration,
 };
 
+/// The protocols we accept inbound requests for, shared by the behaviour with all handlers.
+pub type InboundProtocols = Arc<RwLock<SmallVec<[ProtocolInfo; 2]>>>;
+
+/// An inbound request received by the [`ResponseProtocol`] upgrade together
+/// with the channel for sending back the response.
+type ReceivedRequest = (
+    (RequestId, ProtocolInfo, RequestPayload),
+    oneshot::Sender<ResponsePayload>,
+);
+
 /// A connection handler of a `RequestResponse` protocol.
 #[doc(hidden)]
 pub struct RequestResponseHandler {
     /// The keep-alive timeout of idle connections. A connection is considered
     /// idle if there are no outbound substreams.
     keep_alive_timeout: Duration,
-    /// The timeout for inbound and outbound substreams (i.e. request
-    /// and response processing).
+    /// The timeout for inbound substreams (i.e. request and response
+    /// processing). Outbound requests carry their own timeout.
     substream_timeout: Duration,
     /// The current connection keep-alive.
     keep_alive: KeepAlive,
@@ -62,20 +85,75 @@ pub struct RequestResponseHandler {
     pending_events: VecDeque<RequestResponseHandlerEvent>,
     /// Outbound upgrades waiting to be emitted as an `OutboundSubstreamRequest`.
     outbound: VecDeque<RequestProtocol>,
+    /// Keep-alive guards of the streams handed over via
+    /// [`RequestResponseHandlerEvent::StreamOpened`]. The connection is kept
+    /// alive for as long as any of them is still in use.
+    open_streams: Vec<Weak<()>>,
+    /// The protocols supported for inbound requests.
+    inbound_protocols: InboundProtocols,
+    /// The codecs of the protocols, shared by all handlers.
+    codecs: Codecs,
+    /// The ID of the next inbound request, shared by all handlers.
+    inbound_request_id: Arc<AtomicU64>,
+    /// Inbound upgrades waiting for the incoming request.
+    inbound: FuturesUnordered<BoxFuture<'static, Result<ReceivedRequest, oneshot::Canceled>>>,
+    /// The protocols of inbound requests handed over to the behaviour,
+    /// until the response is sent.
+    inbound_requests: HashMap<RequestId, ProtocolInfo>,
+    /// The inbound substreams opened by the muxer, see [`RejectedProtocols`].
+    inbound_substreams: InboundSubstreams,
 }
 
 impl RequestResponseHandler {
-    pub(super) fn new(keep_alive_timeout: Duration, substream_timeout: Duration) -> Self {
+    pub(super) fn new(
+        inbound_protocols: InboundProtocols,
+        codecs: Codecs,
+        keep_alive_timeout: Duration,
+        substream_timeout: Duration,
+        inbound_request_id: Arc<AtomicU64>,
+    ) -> Self {
         Self {
+            inbound_protocols,
+            codecs,
             keep_alive: KeepAlive::Yes,
             keep_alive_timeout,
             substream_timeout,
             outbound: VecDeque::new(),
+            inbound: FuturesUnordered::new(),
+            inbound_requests: HashMap::new(),
             pending_events: VecDeque::new(),
             pending_error: None,
+            open_streams: Vec::new(),
+            inbound_request_id,
+            inbound_substreams: Default::default(),
         }
     }
 
+    /// The upgrade receiving a request on an inbound substream, see `listen_protocol`.
+    fn response_protocol(
+        &self,
+        request_id: RequestId,
+        request_sender: oneshot::Sender<(RequestId, ProtocolInfo, RequestPayload)>,
+        response_receiver: oneshot::Receiver<ResponsePayload>,
+    ) -> ResponseProtocol {
+        ResponseProtocol {
+            protocols: self
+                .inbound_protocols
+                .read()
+                .expect("Inbound protocols lock should not be poisoned.")
+                .clone(),
+            request_sender,
+            response_receiver,
+            request_id,
+            codecs: self.codecs.clone(),
+        }
+    }
+
+    /// Checks whether any of the streams handed over to the behaviour is still in use.
+    fn has_open_streams(&self) -> bool {
+        self.open_streams.iter().any(|s| s.strong_count() > 0)
+    }
+
     fn on_dial_upgrade_error(
         &mut self,
         DialUpgradeError { info, error }: DialUpgradeError<
@@ -98,6 +176,13 @@ impl RequestResponseHandler {
                     RequestResponseHandlerEvent::OutboundUnsupportedProtocols(info),
                 );
             }
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(err))
+                if PayloadTooLarge::is_cause_of(&err) =>
+            {
+                // We stopped reading the response, the connection is still fine.
+                self.pending_events
+                    .push_back(RequestResponseHandlerEvent::OutboundResponseTooLarge(info));
+            }
             _ => {
                 // Anything else is considered a fatal error or misbehaviour of
                 // the remote peer and results in closing the connection.
@@ -105,31 +190,47 @@ impl RequestResponseHandler {
             }
         }
     }
+
     fn on_listen_upgrade_error(
         &mut self,
-        ListenUpgradeError { info: _, error }: ListenUpgradeError<
+        ListenUpgradeError {
+            info: (request_id, rejections),
+            error,
+        }: ListenUpgradeError<
             <Self as ConnectionHandler>::InboundOpenInfo,
             <Self as ConnectionHandler>::InboundProtocol,
         >,
     ) {
         match error {
-            ConnectionHandlerUpgrErr::Timeout => self
-                .pending_events
-                .push_back(RequestResponseHandlerEvent::InboundTimeout),
+            ConnectionHandlerUpgrErr::Timeout => {
+                // The protocol is known only if the request was received.
+                let protocol = self.inbound_requests.remove(&request_id);
+                self.pending_events
+                    .push_back(RequestResponseHandlerEvent::InboundTimeout(protocol))
+            }
             ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed)) => {
                 // The local peer merely doesn't support the protocol(s) requested.
                 // This is no reason to close the connection, which may
                 // successfully communicate with other protocols already.
                 // An event is reported to permit user code to react to the fact that
                 // the local peer does not support the requested protocol(s).
-                self.pending_events
-                    .push_back(RequestResponseHandlerEvent::InboundUnsupportedProtocols);
+                self.pending_events.push_back(
+                    RequestResponseHandlerEvent::InboundUnsupportedProtocols(
+                        rejections.protocols(),
+                    ),
+                );
+            }
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(_)) => {
+                // Reading the request or writing the response failed, e.g. because the
+                // remote peer reset the substream after probing whether we support the
+                // protocol. This affects only the substream, not the whole connection.
+                self.inbound_requests.remove(&request_id);
             }
             _ => {
                 // Anything else is considered a fatal error or misbehaviour of
                 // the remote peer and results in closing the connection.
-                // self.pending_error = Some(error);
-                unreachable!();
+                self.inbound_requests.remove(&request_id);
+                self.pending_error = Some(error);
             }
         }
     }
@@ -138,32 +239,91 @@ impl RequestResponseHandler {
 /// The events emitted by the [`RequestResponseHandler`].
 #[doc(hidden)]
 pub enum RequestResponseHandlerEvent {
+    /// A request has been received.
+    Request {
+        request_id: RequestId,
+        protocol: ProtocolInfo,
+        request: RequestPayload,
+        sender: oneshot::Sender<ResponsePayload>,
+    },
+    /// A response to an inbound request was omitted as a result
+    /// of dropping the response `sender` of an inbound `Request`.
+    ResponseOmission {
+        request_id: RequestId,
+        protocol: Option<ProtocolInfo>,
+    },
     /// A response has been received.
     Response {
         request_id: RequestId,
+        protocol: ProtocolInfo,
         response: ResponsePayload,
+        request_size: usize,
+        timings: RequestTimings,
+    },
+    /// An outbound stream has been negotiated.
+    StreamOpened {
+        request_id: RequestId,
+        stream: NegotiatedSubstream,
+        /// Keeps the connection alive until dropped.
+        keep_alive: Arc<()>,
     },
     /// An outbound request timed out while sending the request
     /// or waiting for the response.
     OutboundTimeout(RequestId),
     /// An outbound request failed to negotiate a mutually supported protocol.
     OutboundUnsupportedProtocols(RequestId),
+    /// The response to an outbound request exceeds the size limit.
+    OutboundResponseTooLarge(RequestId),
     /// An inbound request timed out while waiting for the request
-    /// or sending the response.
-    InboundTimeout,
+    /// or sending the response. The protocol is known if the request
+    /// was received.
+    InboundTimeout(Option<ProtocolInfo>),
     /// An inbound request failed to negotiate a mutually supported protocol.
-    InboundUnsupportedProtocols,
+    /// Carries the protocols proposed by the remote, if they were recorded.
+    InboundUnsupportedProtocols(Vec<String>),
 }
 
 impl fmt::Debug for RequestResponseHandlerEvent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            RequestResponseHandlerEvent::Request {
+                request_id,
+                protocol,
+                request: _,
+                sender: _,
+            } => f
+                .debug_struct("RequestResponseHandlerEvent::Request")
+                .field("request_id", request_id)
+                .field("protocol", protocol)
+                .finish(),
+            RequestResponseHandlerEvent::ResponseOmission {
+                request_id,
+                protocol,
+            } => f
+                .debug_struct("RequestResponseHandlerEvent::ResponseOmission")
+                .field("request_id", request_id)
+                .field("protocol", protocol)
+                .finish(),
             RequestResponseHandlerEvent::Response {
                 request_id,
+                protocol,
                 response: _,
+                request_size,
+                timings,
             } => f
                 .debug_struct("RequestResponseHandlerEvent::Response")
                 .field("request_id", request_id)
+                .field("protocol", protocol)
+                .field("request_size", request_size)
+                .field("timings", timings)
+                .finish(),
+            RequestResponseHandlerEvent::StreamOpened {
+                request_id,
+                stream: _,
+                keep_alive: _,
+            } => f
+                .debug_struct("RequestResponseHandlerEvent::StreamOpened")
+                .field("request_id", request_id)
                 .finish(),
             RequestResponseHandlerEvent::OutboundTimeout(request_id) => f
                 .debug_tuple("RequestResponseHandlerEvent::OutboundTimeout")
@@ -173,27 +333,104 @@ impl fmt::Debug for RequestResponseHandlerEvent {
                 .debug_tuple("RequestResponseHandlerEvent::OutboundUnsupportedProtocols")
                 .field(request_id)
                 .finish(),
-            RequestResponseHandlerEvent::InboundTimeout => f
+            RequestResponseHandlerEvent::OutboundResponseTooLarge(request_id) => f
+                .debug_tuple("RequestResponseHandlerEvent::OutboundResponseTooLarge")
+                .field(request_id)
+                .finish(),
+            RequestResponseHandlerEvent::InboundTimeout(protocol) => f
                 .debug_tuple("RequestResponseHandlerEvent::InboundTimeout")
+                .field(protocol)
                 .finish(),
-            RequestResponseHandlerEvent::InboundUnsupportedProtocols => f
+            RequestResponseHandlerEvent::InboundUnsupportedProtocols(requested) => f
                 .debug_tuple("RequestResponseHandlerEvent::InboundUnsupportedProtocols")
+                .field(requested)
                 .finish(),
         }
     }
 }
 
+/// Creates the [`RequestResponseHandler`] of a connection once it is established,
+/// handing over the inbound substreams recorded for the connection.
+#[doc(hidden)]
+pub struct RequestResponseHandlerProto {
+    handler: RequestResponseHandler,
+    rejected_protocols: RejectedProtocols,
+}
+
+impl RequestResponseHandlerProto {
+    pub(super) fn new(
+        handler: RequestResponseHandler,
+        rejected_protocols: RejectedProtocols,
+    ) -> Self {
+        Self {
+            handler,
+            rejected_protocols,
+        }
+    }
+}
+
+impl IntoConnectionHandler for RequestResponseHandlerProto {
+    type Handler = RequestResponseHandler;
+
+    fn into_handler(mut self, remote_peer_id: &PeerId, endpoint: &ConnectedPoint) -> Self::Handler {
+        self.handler.inbound_substreams =
+            self.rejected_protocols.connection(remote_peer_id, endpoint);
+        self.handler
+    }
+
+    fn inbound_protocol(&self) -> <Self::Handler as ConnectionHandler>::InboundProtocol {
+        // Only the protocols of the upgrade matter here. Unlike `listen_protocol`, this must
+        // neither take the rejections of an inbound substream nor use up a request ID.
+        let (request_sender, _) = oneshot::channel();
+        let (_, response_receiver) = oneshot::channel();
+        let request_id = RequestId(self.handler.inbound_request_id.load(Ordering::Relaxed));
+        self.handler
+            .response_protocol(request_id, request_sender, response_receiver)
+    }
+}
+
 impl ConnectionHandler for RequestResponseHandler {
     type InEvent = RequestProtocol;
     type OutEvent = RequestResponseHandlerEvent;
     type Error = ConnectionHandlerUpgrErr<io::Error>;
-    type InboundProtocol = DeniedUpgrade;
+    type InboundProtocol = ResponseProtocol;
     type OutboundProtocol = RequestProtocol;
     type OutboundOpenInfo = RequestId;
-    type InboundOpenInfo = ();
+    type InboundOpenInfo = (RequestId, SubstreamRejections);
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
-        SubstreamProtocol::new(DeniedUpgrade, ()).with_timeout(self.substream_timeout)
+        // A channel for notifying the handler when the inbound
+        // upgrade received the request.
+        let (rq_send, rq_recv) = oneshot::channel();
+
+        // A channel for notifying the inbound upgrade when the
+        // response is sent.
+        let (rs_send, rs_recv) = oneshot::channel();
+
+        let request_id = RequestId(self.inbound_request_id.fetch_add(1, Ordering::Relaxed));
+
+        // By keeping all I/O inside the `ResponseProtocol` and thus the
+        // inbound substream upgrade via above channels, we ensure that it
+        // is all subject to the configured timeout without extra bookkeeping
+        // for inbound substreams as well as their timeouts and also make the
+        // implementation of inbound and outbound upgrades symmetric in
+        // this sense.
+        let proto = self.response_protocol(request_id, rq_send, rs_recv);
+
+        // The handler waits for the request to come in. It then emits
+        // `RequestResponseHandlerEvent::Request` together with a
+        // `ResponseChannel`.
+        self.inbound
+            .push(rq_recv.map_ok(move |rq| (rq, rs_send)).boxed());
+
+        // The connection calls `listen_protocol` exactly once per inbound substream, right
+        // after the muxer opened it and in the order the muxer opened them, and nowhere
+        // else (libp2p-swarm 0.41). The n-th call thus takes the rejections of the n-th
+        // inbound substream, see `InboundSubstreams`. Anything else asking for the inbound
+        // protocol must not call it, see `RequestResponseHandlerProto::inbound_protocol`.
+        let rejections = self.inbound_substreams.next_substream().unwrap_or_default();
+
+        SubstreamProtocol::new(proto, (request_id, rejections)).with_timeout(self.substream_timeout)
     }
 
     fn on_behaviour_event(&mut self, request: Self::InEvent) {
@@ -202,12 +439,15 @@ impl ConnectionHandler for RequestResponseHandler {
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
+        if self.has_open_streams() {
+            return KeepAlive::Yes;
+        }
         self.keep_alive
     }
 
     fn poll(
         &mut self,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<ConnectionHandlerEvent<RequestProtocol, RequestId, Self::OutEvent, Self::Error>> {
         // Check for a pending (fatal) error.
         if let Some(err) = self.pending_error.take() {
@@ -222,12 +462,37 @@ impl ConnectionHandler for RequestResponseHandler {
             self.pending_events.shrink_to_fit();
         }
 
+        // Check for inbound requests.
+        while let Poll::Ready(Some(result)) = self.inbound.poll_next_unpin(cx) {
+            match result {
+                Ok(((request_id, protocol, request), sender)) => {
+                    // We received an inbound request.
+                    self.keep_alive = KeepAlive::Yes;
+                    self.inbound_requests.insert(request_id, protocol.clone());
+                    return Poll::Ready(ConnectionHandlerEvent::Custom(
+                        RequestResponseHandlerEvent::Request {
+                            request_id,
+                            protocol,
+                            request,
+                            sender,
+                        },
+                    ));
+                }
+                Err(oneshot::Canceled) => {
+                    // The inbound upgrade has errored or timed out reading
+                    // or waiting for the request. The handler is informed
+                    // via `on_connection_event` call with `ConnectionEvent::ListenUpgradeError`.
+                }
+            }
+        }
+
         // Emit outbound requests.
-        if let Some(request) = self.outbound.pop_front() {
+        if let Some(mut request) = self.outbound.pop_front() {
+            request.substream_requested = Some(Instant::now());
             let info = request.request_id;
+            let timeout = request.substream_timeout();
             return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
-                protocol: SubstreamProtocol::new(request, info)
-                    .with_timeout(self.substream_timeout),
+                protocol: SubstreamProtocol::new(request, info).with_timeout(timeout),
             });
         }
 
@@ -237,7 +502,9 @@ impl ConnectionHandler for RequestResponseHandler {
             self.outbound.shrink_to_fit();
         }
 
-        if self.keep_alive.is_yes() {
+        self.open_streams.retain(|s| s.strong_count() > 0);
+
+        if self.inbound.is_empty() && self.keep_alive.is_yes() && !self.has_open_streams() {
             // No new inbound or outbound requests. However, we may just have
             // started the latest inbound or outbound upgrade(s), so make sure
             // the keep-alive timeout is preceded by the substream timeout.
@@ -258,19 +525,51 @@ impl ConnectionHandler for RequestResponseHandler {
         >,
     ) {
         match event {
-            ConnectionEvent::FullyNegotiatedInbound(_fully_negotiated_inbound) => {
-                unreachable!();
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: sent,
+                info: (request_id, _),
+            }) => {
+                let protocol = self.inbound_requests.remove(&request_id);
+                if !sent {
+                    self.pending_events
+                        .push_back(RequestResponseHandlerEvent::ResponseOmission {
+                            request_id,
+                            protocol,
+                        })
+                }
             }
             ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
-                protocol: response,
+                protocol: output,
                 info: request_id,
-            }) => {
-                self.pending_events
-                    .push_back(RequestResponseHandlerEvent::Response {
-                        request_id,
-                        response,
-                    });
-            }
+            }) => match output {
+                RequestOutput::Response {
+                    protocol,
+                    response,
+                    request_size,
+                    timings,
+                } => {
+                    self.pending_events
+                        .push_back(RequestResponseHandlerEvent::Response {
+                            request_id,
+                            protocol,
+                            response,
+                            request_size,
+                            timings,
+                        });
+                }
+                RequestOutput::Stream(stream) => {
+                    let keep_alive = Arc::new(());
+                    self.open_streams.push(Arc::downgrade(&keep_alive));
+                    // Start the idle timeout afresh once all streams are closed.
+                    self.keep_alive = KeepAlive::Yes;
+                    self.pending_events
+                        .push_back(RequestResponseHandlerEvent::StreamOpened {
+                            request_id,
+                            stream,
+                            keep_alive,
+                        });
+                }
+            },
             ConnectionEvent::DialUpgradeError(dial_upgrade_error) => {
                 self.on_dial_upgrade_error(dial_upgrade_error)
             }
@@ -1,15 +1,24 @@
-//! The definition of a request/response protocol via outbound substream
-//! upgrades. The outbound upgrade sends a request and receives a response.
+//! The definition of a request/response protocol via inbound
+//! and outbound substream upgrades. The inbound upgrade receives a
+//! request and sends a response, the outbound upgrade sends a request
+//! and receives a response, or hands the negotiated substream over
+//! when opening a raw stream. The [`Codec`] of the negotiated protocol
+//! frames the request and the response on the substream.
 
 use crate::peer::RequestId;
 
-pub use libp2p::core::upgrade::ProtocolName;
-
-use libp2p::core::upgrade::{OutboundUpgrade, UpgradeInfo};
-use libp2p::futures::{future::BoxFuture, prelude::*};
+use async_trait::async_trait;
+use libp2p::core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::futures::{channel::oneshot, future::BoxFuture, prelude::*};
 use libp2p::swarm::NegotiatedSubstream;
 use smallvec::SmallVec;
 
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
 // FIXME: Can we use `[u8]` instead? How to avoid closing when sending the data between threads?
@@ -18,13 +27,401 @@ pub type ResponsePayload = Vec<u8>;
 
 pub type ProtocolInfo = SmallVec<[u8; 16]>;
 
+/// The codecs of the protocols, shared by the behaviour with all handlers and upgrades.
+/// Protocols without a codec use [`CloseDelimited`].
+pub type Codecs = Arc<RwLock<HashMap<ProtocolInfo, Arc<dyn Codec>>>>;
+
+/// The maximum size of an inbound request in bytes.
+const MAX_REQUEST_SIZE: usize = 10 * 1024 * 1024;
+
+/// Frames requests and responses of a protocol on the substream.
+///
+/// The codec decides how the reader finds the end of the payload. The upgrades close the
+/// writer after writing the request or the response only when the codec relies on it,
+/// see [`Codec::closes_writer`].
+#[async_trait]
+pub trait Codec: fmt::Debug + Send + Sync {
+    /// Reads a request or a response, fails with [`PayloadTooLarge`] when the payload is
+    /// longer than `max_size` bytes.
+    async fn read_payload(
+        &self,
+        io: &mut (dyn AsyncRead + Unpin + Send),
+        max_size: usize,
+    ) -> io::Result<Vec<u8>>;
+
+    /// Writes a request or a response.
+    async fn write_payload(
+        &self,
+        io: &mut (dyn AsyncWrite + Unpin + Send),
+        payload: &[u8],
+    ) -> io::Result<()>;
+
+    /// Checks that the payload can be written, fails with [`io::ErrorKind::InvalidInput`]
+    /// otherwise. Requests are checked before opening the substream.
+    fn check_payload(&self, _payload: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether closing the writer marks the end of the payload. The substream stays open
+    /// after the payload otherwise.
+    fn closes_writer(&self) -> bool {
+        false
+    }
+}
+
+/// The payload is written as-is and read until the remote peer closes its writer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CloseDelimited;
+
+#[async_trait]
+impl Codec for CloseDelimited {
+    async fn read_payload(
+        &self,
+        io: &mut (dyn AsyncRead + Unpin + Send),
+        max_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        // One byte more than allowed tells us the payload is too large.
+        let mut payload = Vec::new();
+        io.take((max_size as u64).saturating_add(1))
+            .read_to_end(&mut payload)
+            .await?;
+        if payload.len() > max_size {
+            return Err(PayloadTooLarge { max_size }.into());
+        }
+        Ok(payload)
+    }
+
+    async fn write_payload(
+        &self,
+        io: &mut (dyn AsyncWrite + Unpin + Send),
+        payload: &[u8],
+    ) -> io::Result<()> {
+        io.write_all(payload).await?;
+        io.flush().await
+    }
+
+    fn closes_writer(&self) -> bool {
+        true
+    }
+}
+
+/// The payload is prefixed with its length encoded as unsigned varint, the substream
+/// stays open after the payload.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LengthPrefixed;
+
+/// An unsigned varint encoding a `u64` takes at most 10 bytes.
+const MAX_UVARINT_LEN: usize = 10;
+
+#[async_trait]
+impl Codec for LengthPrefixed {
+    async fn read_payload(
+        &self,
+        io: &mut (dyn AsyncRead + Unpin + Send),
+        max_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut len: u64 = 0;
+        for i in 0..MAX_UVARINT_LEN {
+            let mut byte = [0];
+            io.read_exact(&mut byte).await?;
+            len |= u64::from(byte[0] & 0x7f) << (7 * i);
+            if byte[0] & 0x80 == 0 {
+                if len > max_size as u64 {
+                    return Err(PayloadTooLarge { max_size }.into());
+                }
+                let mut payload = vec![0; len as usize];
+                io.read_exact(&mut payload).await?;
+                return Ok(payload);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The length prefix is not a valid unsigned varint",
+        ))
+    }
+
+    async fn write_payload(
+        &self,
+        io: &mut (dyn AsyncWrite + Unpin + Send),
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(MAX_UVARINT_LEN + payload.len());
+        let mut len = payload.len() as u64;
+        while len >= 0x80 {
+            frame.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        frame.push(len as u8);
+        frame.extend_from_slice(payload);
+        io.write_all(&frame).await?;
+        io.flush().await
+    }
+}
+
+/// Requests and responses have a fixed size, e.g. 32 bytes for `/ipfs/ping/1.0.0`.
+/// The substream stays open after the payload.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedLength {
+    pub size: usize,
+}
+
+#[async_trait]
+impl Codec for FixedLength {
+    async fn read_payload(
+        &self,
+        io: &mut (dyn AsyncRead + Unpin + Send),
+        max_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        if self.size > max_size {
+            return Err(PayloadTooLarge { max_size }.into());
+        }
+        let mut payload = vec![0; self.size];
+        io.read_exact(&mut payload).await?;
+        Ok(payload)
+    }
+
+    async fn write_payload(
+        &self,
+        io: &mut (dyn AsyncWrite + Unpin + Send),
+        payload: &[u8],
+    ) -> io::Result<()> {
+        self.check_payload(payload)?;
+        io.write_all(payload).await?;
+        io.flush().await
+    }
+
+    fn check_payload(&self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() != self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The payload must have {} bytes, got {} bytes",
+                    self.size,
+                    payload.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the codec of the given protocol.
+pub fn codec_of(codecs: &Codecs, protocol: &[u8]) -> Arc<dyn Codec> {
+    codecs
+        .read()
+        .expect("Codecs lock should not be poisoned.")
+        .get(protocol)
+        .cloned()
+        .unwrap_or_else(|| Arc::new(CloseDelimited))
+}
+
+/// Response substream upgrade protocol.
+///
+/// Receives a request and sends a response.
+#[derive(Debug)]
+pub struct ResponseProtocol {
+    pub(crate) protocols: SmallVec<[ProtocolInfo; 2]>,
+    pub(crate) request_sender: oneshot::Sender<(RequestId, ProtocolInfo, RequestPayload)>,
+    pub(crate) response_receiver: oneshot::Receiver<ResponsePayload>,
+    pub(crate) request_id: RequestId,
+    pub(crate) codecs: Codecs,
+}
+
+impl UpgradeInfo for ResponseProtocol {
+    type Info = ProtocolInfo;
+    type InfoIter = smallvec::IntoIter<[Self::Info; 2]>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.protocols.clone().into_iter()
+    }
+}
+
+impl InboundUpgrade<NegotiatedSubstream> for ResponseProtocol {
+    /// Whether a response was sent.
+    type Output = bool;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, mut io: NegotiatedSubstream, protocol: Self::Info) -> Self::Future {
+        async move {
+            let codec = codec_of(&self.codecs, &protocol);
+
+            // 1. Read the request - at most 10 MB
+            let request = codec.read_payload(&mut io, MAX_REQUEST_SIZE).await?;
+
+            // 2. Hand the request over to the handler
+            if self
+                .request_sender
+                .send((self.request_id, protocol, request))
+                .is_err()
+            {
+                // The handler was dropped, the connection is going away.
+                return Ok(false);
+            }
+
+            // 3. Wait for the response. Unless closing its writer ended the request, watch
+            // the substream meanwhile: a read into an empty buffer consumes nothing, it
+            // returns once the remote peer sent more data or closed its writer, and fails
+            // only when the substream broke. Muxers report a reset as the end of the
+            // substream, writing the response fails then.
+            let response = if codec.closes_writer() {
+                self.response_receiver.await
+            } else {
+                let mut nothing = [0; 0];
+                match future::select(self.response_receiver, io.read(&mut nothing)).await {
+                    future::Either::Left((response, _)) => response,
+                    future::Either::Right((Err(err), _)) => return Err(err),
+                    future::Either::Right((Ok(_), response_receiver)) => response_receiver.await,
+                }
+            };
+
+            // 4. Write the response, unless the response channel was dropped
+            let sent = match response {
+                Ok(response) => {
+                    codec.write_payload(&mut io, &response).await?;
+                    true
+                }
+                Err(oneshot::Canceled) => false,
+            };
+            if codec.closes_writer() {
+                io.close().await?;
+            }
+            Ok(sent)
+        }
+        .boxed()
+    }
+}
+
 /// Request substream upgrade protocol.
 ///
 /// Sends a request and receives a response.
 pub struct RequestProtocol {
     pub(crate) protocols: SmallVec<[ProtocolInfo; 2]>,
     pub(crate) request_id: RequestId,
-    pub(crate) payload: RequestPayload,
+    pub(crate) kind: RequestKind,
+    pub(crate) codecs: Codecs,
+    /// The time allowed for negotiating the protocol and completing the request.
+    pub(crate) timeout: Duration,
+    /// The request fails when not completed by this time, regardless of the `timeout`.
+    pub(crate) deadline: Option<Instant>,
+    /// When the request was created, see [`RequestTimings`].
+    pub(crate) created: Instant,
+    /// When the handler asked the connection for the outbound substream.
+    pub(crate) substream_requested: Option<Instant>,
+    /// When the connection opened the outbound substream, see [`UpgradeInfo`].
+    pub(crate) substream_opened: Cell<Option<Instant>>,
+}
+
+impl RequestProtocol {
+    /// The timeout of the outbound substream, the request must complete within the
+    /// `timeout` and before the `deadline`.
+    pub fn substream_timeout(&self) -> Duration {
+        match self.deadline {
+            Some(deadline) => self
+                .timeout
+                .min(deadline.saturating_duration_since(Instant::now())),
+            None => self.timeout,
+        }
+    }
+}
+
+/// What to do with the outbound substream once the protocol was negotiated.
+#[derive(Debug)]
+pub enum RequestKind {
+    /// Write the payload and read the response, see [`Codec::closes_writer`].
+    Request {
+        payload: RequestPayload,
+        /// Fail with [`PayloadTooLarge`] when the response is longer.
+        max_response_size: usize,
+    },
+    /// Hand the negotiated substream over to the caller.
+    Stream,
+}
+
+/// The error of the upgrades when the request or the response exceeds the size limit.
+#[derive(Debug)]
+pub struct PayloadTooLarge {
+    pub max_size: usize,
+}
+
+impl PayloadTooLarge {
+    /// Checks whether the upgrade failed because of a payload exceeding the size limit.
+    pub fn is_cause_of(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|e| e.is::<PayloadTooLarge>())
+    }
+}
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The payload exceeds the maximum size of {} bytes",
+            self.max_size
+        )
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+impl From<PayloadTooLarge> for io::Error {
+    fn from(err: PayloadTooLarge) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Records when the first byte was read, see [`RequestTimings::first_response_byte`].
+struct FirstByteReader<'a, R> {
+    inner: &'a mut R,
+    first_byte: Option<Instant>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FirstByteReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 && this.first_byte.is_none() {
+                this.first_byte = Some(Instant::now());
+            }
+        }
+        result
+    }
+}
+
+/// When the phases of a request completed.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestTimings {
+    /// The request was created.
+    pub created: Instant,
+    /// The handler asked the connection for an outbound substream.
+    pub substream_requested: Instant,
+    /// The connection opened the outbound substream, the negotiation started.
+    pub substream_opened: Instant,
+    /// The protocol was negotiated, we started writing the request.
+    pub negotiated: Instant,
+    /// The first byte of the response was received, `None` for an empty response.
+    pub first_response_byte: Option<Instant>,
+    /// The whole response was received.
+    pub completed: Instant,
+}
+
+/// The result of a successful outbound upgrade.
+pub enum RequestOutput {
+    /// The response read from the substream.
+    Response {
+        /// The protocol negotiated for the request.
+        protocol: ProtocolInfo,
+        response: ResponsePayload,
+        /// The size of the request payload in bytes.
+        request_size: usize,
+        timings: RequestTimings,
+    },
+    /// The negotiated substream, see [`RequestKind::Stream`].
+    Stream(NegotiatedSubstream),
 }
 
 impl fmt::Debug for RequestProtocol {
@@ -32,7 +429,10 @@ impl fmt::Debug for RequestProtocol {
         f.debug_struct("RequestProtocol")
             .field("request_id", &self.request_id)
             .field("protocols", &self.protocols)
-            .field("payload", &self.payload)
+            .field("kind", &self.kind)
+            .field("timeout", &self.timeout)
+            .field("deadline", &self.deadline)
+            .field("created", &self.created)
             .finish()
     }
 }
@@ -41,29 +441,65 @@ impl UpgradeInfo for RequestProtocol {
     type Info = ProtocolInfo;
     type InfoIter = smallvec::IntoIter<[Self::Info; 2]>;
 
+    /// The connection asks for the protocols once it opened the outbound substream, right
+    /// before negotiating them. The last call tells when the substream was opened.
     fn protocol_info(&self) -> Self::InfoIter {
+        self.substream_opened.set(Some(Instant::now()));
         self.protocols.clone().into_iter()
     }
 }
 
 impl OutboundUpgrade<NegotiatedSubstream> for RequestProtocol {
-    type Output = ResponsePayload;
+    type Output = RequestOutput;
     type Error = io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
-    fn upgrade_outbound(self, mut io: NegotiatedSubstream, _protocol: Self::Info) -> Self::Future {
+    fn upgrade_outbound(self, mut io: NegotiatedSubstream, protocol: Self::Info) -> Self::Future {
         async move {
+            let negotiated = Instant::now();
+            let substream_requested = self.substream_requested.unwrap_or(negotiated);
+            let substream_opened = self.substream_opened.get().unwrap_or(substream_requested);
+
+            let (payload, max_response_size) = match self.kind {
+                RequestKind::Request {
+                    payload,
+                    max_response_size,
+                } => (payload, max_response_size),
+                RequestKind::Stream => return Ok(RequestOutput::Stream(io)),
+            };
+
+            let codec = codec_of(&self.codecs, &protocol);
+
             // 1. Write the request payload
-            io.write_all(&self.payload).await?;
-            io.flush().await?;
+            codec.write_payload(&mut io, &payload).await?;
+
+            // 2. Signal the end of request substream, unless the codec frames the payload
+            if codec.closes_writer() {
+                io.close().await?;
+            }
 
-            // 2. Signal the end of request substream
-            io.close().await?;
+            // 3. Read back the response
+            let mut reader = FirstByteReader {
+                inner: &mut io,
+                first_byte: None,
+            };
+            let response = codec.read_payload(&mut reader, max_response_size).await?;
+            let first_response_byte = reader.first_byte;
 
-            // 3. Read back the response - at most 10 MB
-            let mut response: ResponsePayload = Default::default();
-            io.take(10 * 1024 * 1024).read_to_end(&mut response).await?;
-            Ok(response)
+            let timings = RequestTimings {
+                created: self.created,
+                substream_requested,
+                substream_opened,
+                negotiated,
+                first_response_byte,
+                completed: Instant::now(),
+            };
+            Ok(RequestOutput::Response {
+                protocol,
+                response,
+                request_size: payload.len(),
+                timings,
+            })
         }
         .boxed()
     }
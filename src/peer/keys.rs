@@ -0,0 +1,92 @@
+//! The identity of a [`super::PeerNode`].
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use libp2p::identity::Keypair;
+
+/// Where the keypair identifying the node comes from.
+#[derive(Debug, Clone, Default)]
+pub enum NodeIdentity {
+    /// Generate a new random keypair on every start, the peer ID changes after each restart.
+    #[default]
+    Ephemeral,
+    /// Use the given keypair.
+    Keypair(Keypair),
+    /// Load the protobuf-encoded keypair from the given file. A new keypair is generated
+    /// and written to the file when the file does not exist yet.
+    ///
+    /// On Unix, the file is created readable by the owner only, and we refuse to load
+    /// key files accessible by other users.
+    File(PathBuf),
+}
+
+impl NodeIdentity {
+    /// Returns the keypair, loading or creating the key file if needed.
+    pub fn into_keypair(self) -> io::Result<Keypair> {
+        match self {
+            NodeIdentity::Ephemeral => Ok(Keypair::generate_ed25519()),
+            NodeIdentity::Keypair(keypair) => Ok(keypair),
+            NodeIdentity::File(path) => load_or_create(&path),
+        }
+    }
+}
+
+fn load_or_create(path: &Path) -> io::Result<Keypair> {
+    match load(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        result => return result,
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    let encoded = keypair
+        .to_protobuf_encoding()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = match options.open(path) {
+        Ok(file) => file,
+        // Somebody else created the key file in the meantime, use their key.
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return load(path),
+        Err(err) => return Err(err),
+    };
+    file.write_all(&encoded)?;
+    file.sync_all()?;
+
+    Ok(keypair)
+}
+
+fn load(path: &Path) -> io::Result<Keypair> {
+    let bytes = fs::read(path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Key file {} must not be accessible by other users (mode {:o})",
+                    path.display(),
+                    mode & 0o777
+                ),
+            ));
+        }
+    }
+
+    Keypair::from_protobuf_encoding(&bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
@@ -0,0 +1,340 @@
+//! Long-lived connectivity probes pinging a set of peers, see [`super::PeerNode::monitor_pings`].
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libp2p::core::{Multiaddr, PeerId};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use super::{ping, NetworkEvent, PeerNode, PeerNodeError};
+
+/// The configuration of a [`PingMonitor`].
+#[derive(Debug, Clone)]
+pub struct PingMonitorConfig {
+    /// How often to ping each peer, see [`PeerNode::ping`].
+    ///
+    /// Each peer is pinged on its own schedule, a ping answered late (or lost) delays only
+    /// the next ping of the same peer.
+    pub interval: Duration,
+    /// How long to wait for a ping, including dialing the peer, before counting it as lost.
+    pub timeout: Duration,
+    /// How many of the latest round-trip times the statistics are computed from.
+    pub window: usize,
+}
+
+impl PingMonitorConfig {
+    /// Rejects a zero interval, timeout or window, the monitor would never record a ping.
+    pub(super) fn validate(&self) -> Result<(), PeerNodeError> {
+        let invalid = if self.interval.is_zero() {
+            "The ping interval must not be zero"
+        } else if self.timeout.is_zero() {
+            "The ping timeout must not be zero"
+        } else if self.window == 0 {
+            "The window of round-trip times must not be empty"
+        } else {
+            return Ok(());
+        };
+        Err(PeerNodeError::InvalidConfig {
+            message: invalid.to_string(),
+        })
+    }
+}
+
+impl Default for PingMonitorConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(20),
+            window: 100,
+        }
+    }
+}
+
+/// The ping statistics of a single peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingStats {
+    pub peer_id: PeerId,
+    /// How many pings the monitor sent in total, or tried to send when dialing the peer failed.
+    pub sent: u64,
+    /// How many pings failed or timed out in total, including failures to dial the peer.
+    pub lost: u64,
+    /// The round-trip time of the latest ping, `None` when it was lost.
+    pub last_rtt: Option<Duration>,
+    /// The statistics of the latest round-trip times, `None` until a ping succeeds.
+    pub rtt: Option<RttStats>,
+}
+
+/// The statistics of the round-trip times in the window of a [`PingMonitor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RttStats {
+    /// How many round-trip times the statistics are computed from.
+    pub samples: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    /// The mean difference between consecutive round-trip times.
+    pub jitter: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+/// Pings a set of peers in the background and keeps their [`PingStats`].
+///
+/// The stats are published as [`NetworkEvent::PingStats`] after each ping. The monitor
+/// stops when dropped or when the node is shut down. It holds [`PeerNode`] handles, the
+/// node keeps running while the monitor is alive, even after all other handles were dropped.
+#[derive(Debug)]
+pub struct PingMonitor {
+    stats: Arc<Mutex<HashMap<PeerId, PeerStats>>>,
+    /// Pinging the peers, one task per peer.
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PingMonitor {
+    pub(super) fn spawn(
+        node: PeerNode,
+        peers: Vec<(PeerId, Multiaddr)>,
+        config: PingMonitorConfig,
+    ) -> Self {
+        let stats: Arc<Mutex<HashMap<PeerId, PeerStats>>> = Arc::new(Mutex::new(
+            peers
+                .iter()
+                .map(|(peer_id, _)| (*peer_id, PeerStats::new(config.window)))
+                .collect(),
+        ));
+
+        let tasks = peers
+            .into_iter()
+            .map(|(peer_id, peer_addr)| {
+                tokio::spawn(monitor_peer(
+                    node.clone(),
+                    peer_id,
+                    peer_addr,
+                    config.clone(),
+                    stats.clone(),
+                ))
+            })
+            .collect();
+
+        Self { stats, tasks }
+    }
+
+    /// Returns the current stats of the given peer, `None` when the peer is not monitored.
+    pub fn stats(&self, peer_id: &PeerId) -> Option<PingStats> {
+        self.stats
+            .lock()
+            .expect("Stats lock should not be poisoned.")
+            .get(peer_id)
+            .map(|stats| stats.to_stats(*peer_id))
+    }
+
+    /// Returns the current stats of all monitored peers.
+    pub fn all_stats(&self) -> Vec<PingStats> {
+        self.stats
+            .lock()
+            .expect("Stats lock should not be poisoned.")
+            .iter()
+            .map(|(peer_id, stats)| stats.to_stats(*peer_id))
+            .collect()
+    }
+}
+
+impl Drop for PingMonitor {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Pings the given peer every interval until the node is shut down.
+async fn monitor_peer(
+    node: PeerNode,
+    peer_id: PeerId,
+    peer_addr: Multiaddr,
+    config: PingMonitorConfig,
+    stats: Arc<Mutex<HashMap<PeerId, PeerStats>>>,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    // Don't send a burst of pings to catch up after a slow ping.
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let result = tokio::time::timeout(config.timeout, node.ping(peer_id, peer_addr.clone()))
+            .await
+            .unwrap_or_else(|_| Err(PeerNodeError::ping(peer_id, &ping::Failure::Timeout)));
+        if let Err(PeerNodeError::ShuttingDown) = result {
+            return;
+        }
+        let peer_stats = {
+            let mut stats = stats.lock().expect("Stats lock should not be poisoned.");
+            let peer_stats = stats
+                .get_mut(&peer_id)
+                .expect("Every monitored peer should have stats.");
+            peer_stats.record(result.ok());
+            peer_stats.to_stats(peer_id)
+        };
+        // There may be no subscribers, that's fine.
+        let _ = node.event_sender.send(NetworkEvent::PingStats(peer_stats));
+    }
+}
+
+/// The pings of a single peer recorded by a [`PingMonitor`].
+#[derive(Debug)]
+struct PeerStats {
+    sent: u64,
+    lost: u64,
+    last_rtt: Option<Duration>,
+    /// The latest round-trip times, the oldest first.
+    rtts: VecDeque<Duration>,
+    window: usize,
+}
+
+impl PeerStats {
+    fn new(window: usize) -> Self {
+        Self {
+            sent: 0,
+            lost: 0,
+            last_rtt: None,
+            rtts: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    fn record(&mut self, rtt: Option<Duration>) {
+        self.sent += 1;
+        self.last_rtt = rtt;
+        match rtt {
+            Some(rtt) => {
+                self.rtts.push_back(rtt);
+                if self.rtts.len() > self.window {
+                    self.rtts.pop_front();
+                }
+            }
+            None => self.lost += 1,
+        }
+    }
+
+    fn to_stats(&self, peer_id: PeerId) -> PingStats {
+        PingStats {
+            peer_id,
+            sent: self.sent,
+            lost: self.lost,
+            last_rtt: self.last_rtt,
+            rtt: RttStats::compute(&self.rtts),
+        }
+    }
+}
+
+impl RttStats {
+    fn compute(rtts: &VecDeque<Duration>) -> Option<Self> {
+        let samples = rtts.len();
+        if samples == 0 {
+            return None;
+        }
+
+        let mut sorted: Vec<Duration> = rtts.iter().copied().collect();
+        sorted.sort();
+        // The nearest-rank percentile.
+        let percentile = |p: usize| sorted[(samples * p).div_ceil(100).max(1) - 1];
+
+        let total: Duration = rtts.iter().sum();
+        let jitter = if samples > 1 {
+            let deltas: Duration = rtts
+                .iter()
+                .zip(rtts.iter().skip(1))
+                .map(|(a, b)| *a.max(b) - *a.min(b))
+                .sum();
+            deltas / (samples as u32 - 1)
+        } else {
+            Duration::ZERO
+        };
+
+        Some(Self {
+            samples,
+            min: sorted[0],
+            avg: total / samples as u32,
+            max: sorted[samples - 1],
+            jitter,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compute(rtts_ms: &[u64]) -> Option<RttStats> {
+        RttStats::compute(&rtts_ms.iter().copied().map(Duration::from_millis).collect())
+    }
+
+    #[test]
+    fn computes_no_stats_without_samples() {
+        assert_eq!(compute(&[]), None);
+    }
+
+    #[test]
+    fn computes_stats_of_a_single_sample() {
+        let rtt = Duration::from_millis(42);
+        assert_eq!(
+            compute(&[42]),
+            Some(RttStats {
+                samples: 1,
+                min: rtt,
+                avg: rtt,
+                max: rtt,
+                jitter: Duration::ZERO,
+                p50: rtt,
+                p90: rtt,
+                p99: rtt,
+            })
+        );
+    }
+
+    #[test]
+    fn computes_stats_of_unordered_samples() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            compute(&[30, 10, 40, 20]),
+            Some(RttStats {
+                samples: 4,
+                min: ms(10),
+                avg: ms(25),
+                max: ms(40),
+                // The differences between consecutive samples: 20, 30 and 20.
+                jitter: Duration::from_nanos(23_333_333),
+                p50: ms(20),
+                p90: ms(40),
+                p99: ms(40),
+            })
+        );
+    }
+
+    #[test]
+    fn computes_jitter_in_sample_order() {
+        let stats = compute(&[10, 30, 10, 30]).unwrap();
+        assert_eq!(stats.jitter, Duration::from_millis(20));
+        let stats = compute(&[10, 10, 30, 30]).unwrap();
+        assert_eq!(stats.jitter, Duration::from_nanos(6_666_666));
+    }
+
+    #[test]
+    fn computes_nearest_rank_percentiles() {
+        let rtts: Vec<u64> = (1..=100).rev().collect();
+        let stats = compute(&rtts).unwrap();
+        assert_eq!(stats.p50, Duration::from_millis(50));
+        assert_eq!(stats.p90, Duration::from_millis(90));
+        assert_eq!(stats.p99, Duration::from_millis(99));
+        assert_eq!(stats.max, Duration::from_millis(100));
+
+        let stats = compute(&[1, 2, 3]).unwrap();
+        assert_eq!(stats.p50, Duration::from_millis(2));
+        assert_eq!(stats.p90, Duration::from_millis(3));
+    }
+}
@@ -0,0 +1,476 @@
+//! Observing multistream-select negotiation of inbound substreams.
+//!
+//! When the remote peer opens a substream for a protocol we don't support, the negotiation
+//! fails with `NegotiationError::Failed` and the protocol names proposed by the remote are
+//! lost. [`RecordingMuxer`] wraps the stream muxer of a connection and watches the messages
+//! exchanged on inbound substreams before the negotiation completes, remembering the proposals
+//! the local peer answered with "na" (not available). The connection handler picks up the
+//! proposals of each inbound substream, see [`InboundSubstreams`].
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerEvent};
+use libp2p::core::{ConnectedPoint, PeerId};
+use libp2p::futures::{AsyncRead, AsyncWrite};
+
+const MSG_MULTISTREAM_1_0: &[u8] = b"/multistream/1.0.0\n";
+const MSG_PROTOCOL_NA: &[u8] = b"na\n";
+const MSG_LS: &[u8] = b"ls\n";
+
+/// Stop watching a substream after this many bytes were exchanged without reaching
+/// an agreement, negotiation messages are much shorter.
+const MAX_RECORDED_BYTES: usize = 4 * 1024;
+
+/// How many rejected protocols to remember per inbound substream.
+const MAX_REJECTED_PER_SUBSTREAM: usize = 16;
+
+/// The protocols rejected on one inbound substream, updated as the negotiation goes on.
+#[derive(Debug, Clone, Default)]
+pub struct SubstreamRejections(Arc<Mutex<Vec<String>>>);
+
+impl SubstreamRejections {
+    /// Returns the protocols rejected so far.
+    pub fn protocols(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .expect("Substream rejections lock should not be poisoned.")
+            .clone()
+    }
+
+    fn push(&self, protocol: String) {
+        let mut protocols = self
+            .0
+            .lock()
+            .expect("Substream rejections lock should not be poisoned.");
+        protocols.push(protocol);
+        if protocols.len() > MAX_REJECTED_PER_SUBSTREAM {
+            protocols.remove(0);
+        }
+    }
+}
+
+/// The inbound substreams of one connection, in the order the muxer opened them.
+///
+/// The swarm asks the connection handler for the inbound protocol right after the muxer
+/// opened an inbound substream, that's when the handler takes the substream's rejections.
+#[derive(Debug, Clone, Default)]
+pub struct InboundSubstreams(Arc<Mutex<VecDeque<SubstreamRejections>>>);
+
+impl InboundSubstreams {
+    /// Removes and returns the rejections of the oldest inbound substream not taken yet,
+    /// `None` if the connection's muxer doesn't record them.
+    pub fn next_substream(&self) -> Option<SubstreamRejections> {
+        self.lock().pop_front()
+    }
+
+    fn push(&self, substream: SubstreamRejections) {
+        self.lock().push_back(substream);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<SubstreamRejections>> {
+        self.0
+            .lock()
+            .expect("Inbound substreams lock should not be poisoned.")
+    }
+}
+
+/// The connections whose muxer records rejected protocols, waiting for their handler.
+type RegisteredConnections = HashMap<(PeerId, ConnectedPoint), Vec<InboundSubstreams>>;
+
+/// Protocols proposed by remote peers that the local peer did not support.
+///
+/// The [`RecordingMuxer`] of each connection registers the connection here, its connection
+/// handler looks it up by the remote peer and the endpoint, see [`RejectedProtocols::connection`].
+#[derive(Debug, Clone, Default)]
+pub struct RejectedProtocols(Arc<Mutex<RegisteredConnections>>);
+
+impl RejectedProtocols {
+    /// Removes and returns the inbound substreams of the connection with the given peer
+    /// and endpoint. Nothing is recorded for connections without a [`RecordingMuxer`].
+    pub fn connection(&self, peer: &PeerId, endpoint: &ConnectedPoint) -> InboundSubstreams {
+        let mut connections = self.lock();
+        let key = (*peer, endpoint.clone());
+        match connections.get_mut(&key) {
+            Some(registered) => {
+                let substreams = registered.remove(0);
+                if registered.is_empty() {
+                    connections.remove(&key);
+                }
+                substreams
+            }
+            None => Default::default(),
+        }
+    }
+
+    fn register(&self, peer: PeerId, endpoint: ConnectedPoint) -> InboundSubstreams {
+        let substreams = InboundSubstreams::default();
+        let mut connections = self.lock();
+        // Forget the connections dropped before their handler was created.
+        connections.retain(|_, registered| {
+            registered.retain(|substreams| Arc::strong_count(&substreams.0) > 1);
+            !registered.is_empty()
+        });
+        connections
+            .entry((peer, endpoint))
+            .or_default()
+            .push(substreams.clone());
+        substreams
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RegisteredConnections> {
+        self.0
+            .lock()
+            .expect("Rejected protocols lock should not be poisoned.")
+    }
+}
+
+/// A stream muxer recording the protocols rejected on inbound substreams.
+pub struct RecordingMuxer<M> {
+    inner: M,
+    substreams: InboundSubstreams,
+}
+
+impl<M> RecordingMuxer<M> {
+    /// Wraps the muxer of the connection with the given peer and endpoint, registering
+    /// the connection in `rejected`.
+    pub fn new(
+        inner: M,
+        peer_id: PeerId,
+        endpoint: ConnectedPoint,
+        rejected: &RejectedProtocols,
+    ) -> Self {
+        Self {
+            inner,
+            substreams: rejected.register(peer_id, endpoint),
+        }
+    }
+}
+
+impl<M> StreamMuxer for RecordingMuxer<M>
+where
+    M: StreamMuxer + Unpin,
+    M::Substream: Unpin,
+{
+    type Substream = RecordingSubstream<M::Substream>;
+    type Error = M::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_inbound(cx).map_ok(|inner| {
+            let rejections = SubstreamRejections::default();
+            this.substreams.push(rejections.clone());
+            let recorder = Recorder::new(rejections);
+            RecordingSubstream {
+                inner,
+                recorder: Some(recorder),
+            }
+        })
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_outbound(cx)
+            .map_ok(|inner| RecordingSubstream {
+                inner,
+                recorder: None,
+            })
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll(cx)
+    }
+}
+
+/// A substream passing all data through, observing the negotiation on inbound substreams.
+pub struct RecordingSubstream<S> {
+    inner: S,
+    /// `None` for outbound substreams and once the negotiation has finished.
+    recorder: Option<Recorder>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingSubstream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        match &result {
+            // The remote gave up on the negotiation (or sent everything it had to say).
+            Poll::Ready(Ok(0)) | Poll::Ready(Err(_)) => this.recorder = None,
+            Poll::Ready(Ok(len)) => {
+                if let Some(recorder) = &mut this.recorder {
+                    if recorder.on_read(&buf[..*len]) {
+                        this.recorder = None;
+                    }
+                }
+            }
+            Poll::Pending => {}
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingSubstream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = &result {
+            if let Some(recorder) = &mut this.recorder {
+                if recorder.on_write(&buf[..*len]) {
+                    this.recorder = None;
+                }
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Tracks the listener side of a multistream-select negotiation.
+struct Recorder {
+    /// Proposals we answered with "na".
+    rejections: SubstreamRejections,
+    /// Bytes received from the remote that don't form a complete message yet.
+    read_buf: Vec<u8>,
+    /// Bytes sent to the remote that don't form a complete message yet.
+    write_buf: Vec<u8>,
+    /// Messages received from the remote waiting for our answer.
+    /// `None` stands for the "ls" request.
+    proposals: Vec<Option<Vec<u8>>>,
+    recorded_bytes: usize,
+}
+
+impl Recorder {
+    fn new(rejections: SubstreamRejections) -> Self {
+        Self {
+            rejections,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            proposals: Vec::new(),
+            recorded_bytes: 0,
+        }
+    }
+
+    /// Returns `true` when the substream should no longer be watched.
+    fn on_read(&mut self, data: &[u8]) -> bool {
+        if self.exceeds_limit(data) {
+            return true;
+        }
+        self.read_buf.extend_from_slice(data);
+        while let Some(message) = next_message(&mut self.read_buf) {
+            match message.as_slice() {
+                MSG_MULTISTREAM_1_0 => {}
+                MSG_LS => self.proposals.push(None),
+                _ => self.proposals.push(Some(message)),
+            }
+        }
+        false
+    }
+
+    /// Returns `true` when the substream should no longer be watched.
+    fn on_write(&mut self, data: &[u8]) -> bool {
+        if self.exceeds_limit(data) {
+            return true;
+        }
+        self.write_buf.extend_from_slice(data);
+        while let Some(message) = next_message(&mut self.write_buf) {
+            if message == MSG_MULTISTREAM_1_0 || self.proposals.is_empty() {
+                continue;
+            }
+            match self.proposals.remove(0) {
+                Some(proposal) if message == MSG_PROTOCOL_NA => {
+                    let name = proposal.strip_suffix(b"\n").unwrap_or(&proposal);
+                    self.rejections
+                        .push(String::from_utf8_lossy(name).into_owned());
+                }
+                // We have accepted the protocol, there is nothing to record.
+                Some(proposal) if message == proposal => return true,
+                // The answer to an "ls" request or something we don't understand.
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn exceeds_limit(&mut self, data: &[u8]) -> bool {
+        self.recorded_bytes += data.len();
+        self.recorded_bytes > MAX_RECORDED_BYTES
+    }
+}
+
+/// An unsigned varint encoding a `u64` takes at most 10 bytes.
+const MAX_UVARINT_LEN: usize = 10;
+
+/// Removes the next complete message from the buffer.
+///
+/// Multistream-select messages are prefixed with their length encoded as unsigned varint.
+/// Returns `None` until the whole message was buffered, and for a malformed length prefix,
+/// the substream is no longer watched once it exceeds [`MAX_RECORDED_BYTES`] then.
+fn next_message(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let mut len = 0u64;
+    for (ix, byte) in buf.iter().enumerate().take(MAX_UVARINT_LEN) {
+        len |= u64::from(byte & 0x7f) << (7 * ix);
+        if byte & 0x80 == 0 {
+            let start = ix + 1;
+            let end = usize::try_from(len).ok()?.checked_add(start)?;
+            if buf.len() < end {
+                return None;
+            }
+            let message = buf[start..end].to_vec();
+            buf.drain(..end);
+            return Some(message);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Prefixes the message with its length.
+    fn frame(message: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        let mut len = message.len();
+        while len >= 0x80 {
+            frame.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        frame.push(len as u8);
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    fn frames(messages: &[&[u8]]) -> Vec<u8> {
+        messages.iter().flat_map(|message| frame(message)).collect()
+    }
+
+    fn recorder() -> (Recorder, SubstreamRejections) {
+        let rejections = SubstreamRejections::default();
+        (Recorder::new(rejections.clone()), rejections)
+    }
+
+    #[test]
+    fn records_rejected_proposals_until_one_is_accepted() {
+        let (mut recorder, rejections) = recorder();
+        assert!(!recorder.on_read(&frames(&[MSG_MULTISTREAM_1_0, b"/foo/2.0.0\n"])));
+        assert!(!recorder.on_write(&frames(&[MSG_MULTISTREAM_1_0, MSG_PROTOCOL_NA])));
+        assert_eq!(rejections.protocols(), vec!["/foo/2.0.0"]);
+
+        assert!(!recorder.on_read(&frame(b"/foo/1.0.0\n")));
+        assert!(
+            recorder.on_write(&frame(b"/foo/1.0.0\n")),
+            "The negotiation is over once a protocol was accepted"
+        );
+        assert_eq!(rejections.protocols(), vec!["/foo/2.0.0"]);
+    }
+
+    #[test]
+    fn skips_the_answer_to_ls() {
+        let (mut recorder, rejections) = recorder();
+        assert!(!recorder.on_read(&frames(&[MSG_MULTISTREAM_1_0, MSG_LS, b"/foo/2.0.0\n"])));
+        // The list of protocols is itself a message of length-prefixed protocol names.
+        let protocols = frames(&[b"/bar/1.0.0\n", b"\n"]);
+        assert!(!recorder.on_write(&frames(&[MSG_MULTISTREAM_1_0, &protocols, MSG_PROTOCOL_NA])));
+        assert_eq!(rejections.protocols(), vec!["/foo/2.0.0"]);
+    }
+
+    #[test]
+    fn records_messages_split_across_reads_and_writes() {
+        let (mut recorder, rejections) = recorder();
+        let read = frames(&[MSG_MULTISTREAM_1_0, b"/foo/2.0.0\n", b"/foo/1.0.0\n"]);
+        let written = frames(&[MSG_MULTISTREAM_1_0, MSG_PROTOCOL_NA, MSG_PROTOCOL_NA]);
+        for byte in read.chunks(1) {
+            assert!(!recorder.on_read(byte));
+        }
+        for chunk in written.chunks(3) {
+            assert!(!recorder.on_write(chunk));
+        }
+        assert_eq!(rejections.protocols(), vec!["/foo/2.0.0", "/foo/1.0.0"]);
+    }
+
+    #[test]
+    fn stops_recording_after_the_byte_limit() {
+        let (mut recorder, rejections) = recorder();
+        let proposals: Vec<Vec<u8>> = (0..)
+            .map(|i| frame(format!("/foo/{i}.0.0\n").as_bytes()))
+            .take_while({
+                let mut total = 0;
+                move |proposal| {
+                    total += proposal.len();
+                    total <= MAX_RECORDED_BYTES
+                }
+            })
+            .collect();
+        for proposal in &proposals {
+            assert!(!recorder.on_read(proposal));
+        }
+        assert!(recorder.on_read(&frame(b"/bar/1.0.0\n")));
+        assert!(rejections.protocols().is_empty());
+    }
+
+    #[test]
+    fn keeps_only_the_latest_rejections() {
+        let (mut recorder, rejections) = recorder();
+        for i in 0..MAX_REJECTED_PER_SUBSTREAM + 2 {
+            assert!(!recorder.on_read(&frame(format!("/foo/{i}.0.0\n").as_bytes())));
+            assert!(!recorder.on_write(&frame(MSG_PROTOCOL_NA)));
+        }
+        let protocols = rejections.protocols();
+        assert_eq!(protocols.len(), MAX_REJECTED_PER_SUBSTREAM);
+        assert_eq!(protocols[0], "/foo/2.0.0");
+    }
+
+    #[test]
+    fn parses_length_prefixes_of_any_size() {
+        for len in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 0x20_0000] {
+            let message = vec![b'x'; len];
+            let mut buf = frame(&message);
+            buf.extend_from_slice(&frame(b"next"));
+
+            let mut partial = buf[..buf.len() - 6].to_vec();
+            assert_eq!(next_message(&mut partial), None, "length {len}");
+
+            assert_eq!(next_message(&mut buf), Some(message), "length {len}");
+            assert_eq!(next_message(&mut buf), Some(b"next".to_vec()));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_length_prefixes() {
+        let mut buf = vec![0xff; MAX_UVARINT_LEN + 1];
+        assert_eq!(next_message(&mut buf), None);
+        assert_eq!(buf.len(), MAX_UVARINT_LEN + 1);
+    }
+}
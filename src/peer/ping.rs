@@ -0,0 +1,475 @@
+// This code is based on libp2p with the following notice:
+//
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! The ping protocol of `libp2p-ping` 0.41, extended with pings on demand.
+//!
+//! Like upstream, each connection pings the remote peer periodically over a single outbound
+//! substream and answers the pings of the remote peer. [`Behaviour::ping`] additionally sends
+//! a ping right away on that substream. Opening another ping substream instead would make
+//! the remote drop the previous one, remote peers keep a single inbound ping substream.
+
+use std::collections::VecDeque;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{io, mem};
+
+use libp2p::core::upgrade::{NegotiationError, ReadyUpgrade, UpgradeError};
+use libp2p::core::{connection::ConnectionId, PeerId};
+use libp2p::futures::{future::BoxFuture, prelude::*};
+use libp2p::swarm::handler::{
+    ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr,
+    DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound, KeepAlive,
+};
+use libp2p::swarm::{
+    behaviour::FromSwarm, NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction,
+    NotifyHandler, PollParameters, SubstreamProtocol,
+};
+use rand::{distributions, thread_rng, Rng};
+use tokio::time::Sleep;
+
+pub use libp2p::ping::{Failure, Success, PROTOCOL_NAME};
+
+const PING_SIZE: usize = 32;
+
+/// The configuration of the pings, see [`super::PeerNode::ping`].
+#[derive(Debug, Clone)]
+pub struct PingConfig {
+    /// How long to wait for the response to an outbound ping.
+    pub timeout: Duration,
+    /// The time between a successful outbound ping and the next one sent in the background.
+    pub interval: Duration,
+    /// The number of consecutive failed outbound pings after which the connection is closed.
+    pub max_failures: NonZeroU32,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(20),
+            interval: Duration::from_secs(15),
+            max_failures: NonZeroU32::new(1).expect("1 != 0"),
+        }
+    }
+}
+
+/// Identifies a ping sent on demand, see [`Behaviour::ping`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PingId(u64);
+
+/// The result of an inbound or outbound ping.
+#[derive(Debug)]
+pub struct Event {
+    pub peer: PeerId,
+    pub result: Result<Success, Failure>,
+    /// The pings on demand answered by an outbound ping, empty for background pings.
+    pub requests: Vec<PingId>,
+}
+
+/// Answers the pings of remote peers and pings them periodically and on demand.
+pub struct Behaviour {
+    config: PingConfig,
+    next_ping_id: PingId,
+    events: VecDeque<NetworkBehaviourAction<Event, Handler>>,
+}
+
+impl Behaviour {
+    pub fn new(config: PingConfig) -> Self {
+        Self {
+            config,
+            next_ping_id: PingId(1),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Pings the given connected peer right away, or right after the ping in flight on the
+    /// connection. The result is reported by the [`Event`] listing the returned ID.
+    pub fn ping(&mut self, peer: PeerId) -> PingId {
+        let ping_id = self.next_ping_id;
+        self.next_ping_id = PingId(ping_id.0 + 1);
+        self.events
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id: peer,
+                handler: NotifyHandler::Any,
+                event: ping_id,
+            });
+        ping_id
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = Handler;
+    type OutEvent = Event;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        Handler::new(self.config.clone())
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer: PeerId,
+        _: ConnectionId,
+        (requests, result): HandlerEvent,
+    ) {
+        self.events
+            .push_back(NetworkBehaviourAction::GenerateEvent(Event {
+                peer,
+                result,
+                requests,
+            }));
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionEstablished(_)
+            | FromSwarm::ConnectionClosed(_)
+            | FromSwarm::AddressChange(_)
+            | FromSwarm::DialFailure(_)
+            | FromSwarm::ListenFailure(_)
+            | FromSwarm::NewListener(_)
+            | FromSwarm::NewListenAddr(_)
+            | FromSwarm::ExpiredListenAddr(_)
+            | FromSwarm::ListenerError(_)
+            | FromSwarm::ListenerClosed(_)
+            | FromSwarm::NewExternalAddr(_)
+            | FromSwarm::ExpiredExternalAddr(_) => {}
+        }
+    }
+}
+
+/// The pings on demand answered by an outbound ping together with its result.
+pub type HandlerEvent = (Vec<PingId>, Result<Success, Failure>);
+
+type PingFuture = BoxFuture<'static, Result<(NegotiatedSubstream, Duration), io::Error>>;
+type PongFuture = BoxFuture<'static, Result<NegotiatedSubstream, io::Error>>;
+
+/// The state of the outbound ping substream.
+enum OutboundState {
+    /// A new substream is being negotiated.
+    OpenStream,
+    /// The substream is idle, waiting to send the next ping.
+    Idle(NegotiatedSubstream),
+    /// A ping is being sent.
+    Ping(PingFuture),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// We are inactive because the other peer doesn't support ping.
+    Inactive {
+        /// Whether or not we've reported the missing support yet.
+        reported: bool,
+    },
+    /// We are actively pinging the other peer.
+    Active,
+}
+
+/// Pings the remote peer of a connection and answers its pings.
+pub struct Handler {
+    config: PingConfig,
+    /// The delay to the next ping as well as the ping timeout.
+    timer: Pin<Box<Sleep>>,
+    /// Outbound ping failures that are pending to be processed by `poll()`.
+    pending_errors: VecDeque<Failure>,
+    /// The number of consecutive ping failures, reset by each successful ping.
+    failures: u32,
+    outbound: Option<OutboundState>,
+    /// Waits for the next inbound ping to be answered.
+    inbound: Option<PongFuture>,
+    state: State,
+    /// Pings on demand waiting for the next outbound ping.
+    requested: Vec<PingId>,
+    /// Pings on demand answered by the outbound ping in flight.
+    in_flight: Vec<PingId>,
+}
+
+impl Handler {
+    fn new(config: PingConfig) -> Self {
+        Self {
+            config,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            pending_errors: VecDeque::with_capacity(2),
+            failures: 0,
+            outbound: None,
+            inbound: None,
+            state: State::Active,
+            requested: Vec::new(),
+            in_flight: Vec::new(),
+        }
+    }
+
+    fn reset_timer(&mut self, delay: Duration) {
+        self.timer
+            .as_mut()
+            .reset(tokio::time::Instant::now() + delay);
+    }
+
+    /// Sends the next ping, answering the pings on demand requested so far.
+    fn send_ping(&mut self, stream: NegotiatedSubstream) {
+        self.reset_timer(self.config.timeout);
+        self.in_flight = mem::take(&mut self.requested);
+        self.outbound = Some(OutboundState::Ping(send_ping(stream).boxed()));
+    }
+
+    fn on_dial_upgrade_error(
+        &mut self,
+        DialUpgradeError { error, .. }: DialUpgradeError<
+            <Self as ConnectionHandler>::OutboundOpenInfo,
+            <Self as ConnectionHandler>::OutboundProtocol,
+        >,
+    ) {
+        self.outbound = None; // Request a new substream on the next `poll`.
+
+        let error = match error {
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed)) => {
+                debug_assert_eq!(self.state, State::Active);
+
+                self.state = State::Inactive { reported: false };
+                return;
+            }
+            // Note: This timeout only covers protocol negotiation.
+            ConnectionHandlerUpgrErr::Timeout => Failure::Timeout,
+            e => Failure::Other { error: Box::new(e) },
+        };
+
+        self.pending_errors.push_front(error);
+    }
+}
+
+impl ConnectionHandler for Handler {
+    type InEvent = PingId;
+    type OutEvent = HandlerEvent;
+    type Error = Failure;
+    type InboundProtocol = ReadyUpgrade<&'static [u8]>;
+    type OutboundProtocol = ReadyUpgrade<&'static [u8]>;
+    type OutboundOpenInfo = ();
+    type InboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<ReadyUpgrade<&'static [u8]>, ()> {
+        SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), ())
+    }
+
+    fn on_behaviour_event(&mut self, ping_id: PingId) {
+        self.requested.push(ping_id);
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::No
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<ReadyUpgrade<&'static [u8]>, (), HandlerEvent, Self::Error>>
+    {
+        match self.state {
+            State::Inactive { reported: true } => {
+                if self.requested.is_empty() {
+                    return Poll::Pending; // nothing to do on this connection
+                }
+                let requests = mem::take(&mut self.requested);
+                return Poll::Ready(ConnectionHandlerEvent::Custom((
+                    requests,
+                    Err(Failure::Unsupported),
+                )));
+            }
+            State::Inactive { reported: false } => {
+                self.state = State::Inactive { reported: true };
+                let requests = mem::take(&mut self.requested);
+                return Poll::Ready(ConnectionHandlerEvent::Custom((
+                    requests,
+                    Err(Failure::Unsupported),
+                )));
+            }
+            State::Active => {}
+        }
+
+        // Respond to inbound pings.
+        if let Some(fut) = self.inbound.as_mut() {
+            match fut.poll_unpin(cx) {
+                Poll::Pending => {}
+                Poll::Ready(Err(_)) => self.inbound = None,
+                Poll::Ready(Ok(stream)) => {
+                    // A ping from a remote peer has been answered, wait for the next.
+                    self.inbound = Some(recv_ping(stream).boxed());
+                    return Poll::Ready(ConnectionHandlerEvent::Custom((
+                        Vec::new(),
+                        Ok(Success::Pong),
+                    )));
+                }
+            }
+        }
+
+        loop {
+            // Check for outbound ping failures.
+            if let Some(error) = self.pending_errors.pop_back() {
+                self.failures += 1;
+
+                // Note: For backward-compatibility, with configured
+                // `max_failures == 1`, the first failure is always "free"
+                // and silent. This allows peers who still use a new substream
+                // for each ping to have successful ping exchanges with peers
+                // that use a single substream, since every successful ping
+                // resets `failures` to `0`, while at the same time emitting
+                // events only for `max_failures - 1` failures, as before.
+                if self.failures > 1 || self.config.max_failures.get() > 1 {
+                    if self.failures >= self.config.max_failures.get() {
+                        return Poll::Ready(ConnectionHandlerEvent::Close(error));
+                    }
+
+                    let requests = mem::take(&mut self.in_flight);
+                    return Poll::Ready(ConnectionHandlerEvent::Custom((requests, Err(error))));
+                }
+                // The pings on demand wait for the ping on the next substream.
+                let mut requests = mem::take(&mut self.in_flight);
+                requests.append(&mut self.requested);
+                self.requested = requests;
+            }
+
+            // Continue outbound pings.
+            match self.outbound.take() {
+                Some(OutboundState::Ping(mut ping)) => match ping.poll_unpin(cx) {
+                    Poll::Pending => {
+                        if self.timer.as_mut().poll(cx).is_ready() {
+                            self.pending_errors.push_front(Failure::Timeout);
+                        } else {
+                            self.outbound = Some(OutboundState::Ping(ping));
+                            break;
+                        }
+                    }
+                    Poll::Ready(Ok((stream, rtt))) => {
+                        self.failures = 0;
+                        let requests = mem::take(&mut self.in_flight);
+                        if self.requested.is_empty() {
+                            self.reset_timer(self.config.interval);
+                            self.outbound = Some(OutboundState::Idle(stream));
+                        } else {
+                            // The pings requested meanwhile don't wait for the interval.
+                            self.send_ping(stream);
+                        }
+                        return Poll::Ready(ConnectionHandlerEvent::Custom((
+                            requests,
+                            Ok(Success::Ping { rtt }),
+                        )));
+                    }
+                    Poll::Ready(Err(e)) => {
+                        self.pending_errors
+                            .push_front(Failure::Other { error: Box::new(e) });
+                    }
+                },
+                Some(OutboundState::Idle(stream)) => {
+                    if self.requested.is_empty() && self.timer.as_mut().poll(cx).is_pending() {
+                        self.outbound = Some(OutboundState::Idle(stream));
+                        break;
+                    }
+                    self.send_ping(stream);
+                }
+                Some(OutboundState::OpenStream) => {
+                    self.outbound = Some(OutboundState::OpenStream);
+                    break;
+                }
+                None => {
+                    self.outbound = Some(OutboundState::OpenStream);
+                    let protocol = SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), ())
+                        .with_timeout(self.config.timeout);
+                    return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                        protocol,
+                    });
+                }
+            }
+        }
+
+        Poll::Pending
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: stream,
+                ..
+            }) => {
+                self.inbound = Some(recv_ping(stream).boxed());
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: stream,
+                ..
+            }) => self.send_ping(stream),
+            ConnectionEvent::DialUpgradeError(dial_upgrade_error) => {
+                self.on_dial_upgrade_error(dial_upgrade_error)
+            }
+            ConnectionEvent::AddressChange(_) | ConnectionEvent::ListenUpgradeError(_) => {}
+        }
+    }
+}
+
+/// Sends a ping and waits for the pong.
+async fn send_ping<S>(mut stream: S) -> io::Result<(S, Duration)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let payload: [u8; PING_SIZE] = thread_rng().sample(distributions::Standard);
+    stream.write_all(&payload).await?;
+    stream.flush().await?;
+    let started = Instant::now();
+    let mut recv_payload = [0u8; PING_SIZE];
+    stream.read_exact(&mut recv_payload).await?;
+    if recv_payload == payload {
+        Ok((stream, started.elapsed()))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Ping payload mismatch",
+        ))
+    }
+}
+
+/// Waits for a ping and sends a pong.
+async fn recv_ping<S>(mut stream: S) -> io::Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut payload = [0u8; PING_SIZE];
+    stream.read_exact(&mut payload).await?;
+    stream.write_all(&payload).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
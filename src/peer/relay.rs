@@ -0,0 +1,178 @@
+//! Connecting to peers through circuit relays, see `/p2p-circuit` addresses, and relaying
+//! connections for other peers, see [`super::PeerNodeConfig::relay_server`].
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::multiaddr::Protocol;
+use libp2p::relay::v2::relay as relay_server;
+use libp2p::swarm::DialError;
+
+/// The limits of the circuit relay server.
+#[derive(Debug, Clone)]
+pub struct RelayServerConfig {
+    /// How many reservations the relay accepts in total.
+    pub max_reservations: usize,
+    /// How many reservations a single peer can hold.
+    pub max_reservations_per_peer: usize,
+    /// How long a reservation is valid, the peers renew their reservations before they expire.
+    pub reservation_duration: Duration,
+    /// How many circuits can be open at the same time in total.
+    pub max_circuits: usize,
+    /// How many circuits a single peer can open at the same time.
+    pub max_circuits_per_peer: usize,
+    /// How long a circuit can stay open.
+    pub max_circuit_duration: Duration,
+    /// How many bytes can be relayed through a circuit in each direction.
+    pub max_circuit_bytes: u64,
+    /// Our public addresses, sent to the peers making reservations.
+    ///
+    /// Only these addresses are advertised, our listen addresses may be private or
+    /// unspecified (`0.0.0.0`). Peers cannot make reservations without an address.
+    pub external_addrs: Vec<Multiaddr>,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration: Duration::from_secs(60 * 60),
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: Duration::from_secs(2 * 60),
+            max_circuit_bytes: 1 << 17,
+            external_addrs: Vec::new(),
+        }
+    }
+}
+
+impl RelayServerConfig {
+    pub(super) fn into_behaviour(self, peer_id: PeerId) -> relay_server::Relay {
+        relay_server::Relay::new(
+            peer_id,
+            relay_server::Config {
+                max_reservations: self.max_reservations,
+                max_reservations_per_peer: self.max_reservations_per_peer,
+                reservation_duration: self.reservation_duration,
+                max_circuits: self.max_circuits,
+                max_circuits_per_peer: self.max_circuits_per_peer,
+                max_circuit_duration: self.max_circuit_duration,
+                max_circuit_bytes: self.max_circuit_bytes,
+                ..Default::default()
+            },
+        )
+    }
+}
+
+/// The activity of the circuit relay server, see [`super::PeerNode::relay_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayStats {
+    /// The peers holding reservations.
+    pub reserved_peers: HashSet<PeerId>,
+    pub active_circuits: Vec<ActiveCircuit>,
+    /// How many circuits were opened in total.
+    pub accepted_circuits: u64,
+    /// How many circuits were refused, e.g. because of the limits or because the destination
+    /// peer has no reservation.
+    pub denied_circuits: u64,
+    /// How many circuits could not be opened, e.g. because the destination peer
+    /// did not accept the connection.
+    pub failed_circuits: u64,
+    /// How many reservations were refused because of the limits.
+    pub denied_reservations: u64,
+}
+
+/// A connection relayed between two peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveCircuit {
+    /// The peer that opened the circuit.
+    pub src_peer_id: PeerId,
+    /// The peer holding the reservation.
+    pub dst_peer_id: PeerId,
+    pub opened: Instant,
+}
+
+impl RelayStats {
+    pub(super) fn record(&mut self, event: &relay_server::Event) {
+        match event {
+            relay_server::Event::ReservationReqAccepted { src_peer_id, .. } => {
+                self.reserved_peers.insert(*src_peer_id);
+            }
+            relay_server::Event::ReservationTimedOut { src_peer_id } => {
+                self.reserved_peers.remove(src_peer_id);
+            }
+            relay_server::Event::ReservationReqDenied { .. } => self.denied_reservations += 1,
+            relay_server::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+                ..
+            } => {
+                self.accepted_circuits += 1;
+                self.active_circuits.push(ActiveCircuit {
+                    src_peer_id: *src_peer_id,
+                    dst_peer_id: *dst_peer_id,
+                    opened: Instant::now(),
+                });
+            }
+            relay_server::Event::CircuitReqDenied { .. } => self.denied_circuits += 1,
+            relay_server::Event::CircuitReqOutboundConnectFailed { .. }
+            | relay_server::Event::CircuitReqAcceptFailed { .. } => self.failed_circuits += 1,
+            relay_server::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                ..
+            } => {
+                let closed = self.active_circuits.iter().position(|circuit| {
+                    circuit.src_peer_id == *src_peer_id && circuit.dst_peer_id == *dst_peer_id
+                });
+                if let Some(index) = closed {
+                    self.active_circuits.remove(index);
+                }
+            }
+            // Failures to talk to the peers don't change the reservations and circuits.
+            _ => {}
+        }
+    }
+
+    /// The reservations of the peer end when the last connection to the peer is closed.
+    pub(super) fn peer_disconnected(&mut self, peer_id: &PeerId) {
+        self.reserved_peers.remove(peer_id);
+    }
+}
+
+/// Returns the relay of a `/p2p-circuit` address, e.g. `RELAY` in
+/// `/ip4/1.2.3.4/tcp/4001/p2p/RELAY/p2p-circuit/p2p/DESTINATION`.
+pub(super) fn relay_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2p(hash) => relay = PeerId::from_multihash(hash).ok(),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the relay of a failed dial when all the addresses tried were `/p2p-circuit`
+/// addresses of the same relay.
+pub(super) fn relay_of_failed_dial(error: &DialError) -> Option<PeerId> {
+    let addresses = match error {
+        DialError::Transport(errors) if !errors.is_empty() => errors,
+        _ => return None,
+    };
+    let relay = relay_peer_id(&addresses[0].0)?;
+    addresses
+        .iter()
+        .all(|(addr, _)| relay_peer_id(addr) == Some(relay))
+        .then_some(relay)
+}
+
+/// The address to listen on for connections relayed by the given relay.
+pub(super) fn circuit_addr(relay_peer_id: PeerId, relay_addr: Multiaddr) -> Multiaddr {
+    relay_addr
+        .with(Protocol::P2p(relay_peer_id.into()))
+        .with(Protocol::P2pCircuit)
+}
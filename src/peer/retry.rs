@@ -0,0 +1,73 @@
+//! Retrying requests that failed for transient reasons.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::behaviour::OutboundFailure;
+
+/// When and how often [`super::PeerNode`] sends a failed request again.
+///
+/// Retrying is opt-in, see [`super::RequestOptions::retry`]. The peer is dialed again
+/// when the connection is gone.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How many times to send the request at most, including the first attempt.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry, the backoff doubles with each retry.
+    pub initial_backoff: Duration,
+    /// The upper bound of the backoff.
+    pub max_backoff: Duration,
+    /// Wait a random duration between half of the backoff and the full backoff, so that
+    /// requests failed at the same time are not retried at the same time again.
+    pub jitter: bool,
+    /// Retry when the peer could not be dialed.
+    pub retry_on_dial_failure: bool,
+    /// Retry when the connection was closed before the response was received.
+    pub retry_on_connection_closed: bool,
+    /// Retry when the request timed out, disabled by default.
+    ///
+    /// The remote peer may have processed the request already, enable this
+    /// for idempotent requests only.
+    pub retry_on_timeout: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: true,
+            retry_on_dial_failure: true,
+            retry_on_connection_closed: true,
+            retry_on_timeout: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Checks whether a request failed with the given error can be sent again.
+    pub(super) fn is_retryable(&self, error: &OutboundFailure) -> bool {
+        match error {
+            OutboundFailure::DialFailure => self.retry_on_dial_failure,
+            OutboundFailure::ConnectionClosed => self.retry_on_connection_closed,
+            OutboundFailure::Timeout => self.retry_on_timeout,
+            OutboundFailure::UnsupportedProtocols | OutboundFailure::ResponseTooLarge => false,
+        }
+    }
+
+    /// How long to wait before sending the request again after the given number of attempts.
+    pub(super) fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        if self.jitter {
+            rand::thread_rng().gen_range(backoff / 2..=backoff)
+        } else {
+            backoff
+        }
+    }
+}